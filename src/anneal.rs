@@ -0,0 +1,149 @@
+use crate::common::*;
+use crate::geometry::*;
+use crate::incremental::*;
+use crate::score::*;
+use anyhow::Result;
+use clap::ValueEnum;
+use rand::prelude::*;
+use rand::rngs::SmallRng;
+use std::f64::consts::PI;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Schedule {
+    Exponential,
+    Linear,
+}
+
+pub struct AnnealConfig {
+    pub start_temp: f64,
+    pub end_temp: f64,
+    pub schedule: Schedule,
+    pub time_limit: Duration,
+    // a random seed is drawn and printed when not given
+    pub seed: Option<u64>,
+    // maximum length of a nudge
+    pub step: f64,
+}
+
+impl AnnealConfig {
+    fn temperature(&self, progress: f64) -> f64 {
+        match self.schedule {
+            Schedule::Exponential => {
+                self.start_temp * (self.end_temp / self.start_temp).powf(progress)
+            }
+            Schedule::Linear => self.start_temp + (self.end_temp - self.start_temp) * progress,
+        }
+    }
+}
+
+enum Move {
+    Nudge(usize, Point),
+    Swap(usize, usize),
+    Volume(usize, f64),
+}
+
+pub fn anneal(prob: &Problem, sol: &Solution, config: &AnnealConfig) -> Result<Solution> {
+    ensure_valid(prob, sol)?;
+    let seed = config.seed.unwrap_or_else(rand::random);
+    eprintln!("seed: {}", seed);
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let m = prob.musicians.len();
+    let mut state = IncrementalScore::new(prob, sol);
+    let mut best = state.solution();
+    let mut best_score = state.score();
+    let mut iterations = 0;
+    let mut accepted = 0;
+    let start = Instant::now();
+    if m == 0 {
+        return Ok(best);
+    }
+    loop {
+        let progress = start.elapsed().as_secs_f64() / config.time_limit.as_secs_f64();
+        if progress >= 1.0 {
+            break;
+        }
+        let temp = config.temperature(progress);
+        let before = state.score();
+        let undo = match rng.gen_range(0..3) {
+            0 => {
+                let midx = rng.gen_range(0..m);
+                let from = state.placements()[midx];
+                let angle = rng.gen_range(-PI..PI);
+                let length = rng.gen_range(0.0..config.step);
+                let to = from
+                    + Point {
+                        x: length * angle.cos(),
+                        y: length * angle.sin(),
+                    };
+                if !is_feasible_place(prob, state.placements(), midx, to) {
+                    continue;
+                }
+                state.move_musician(prob, midx, to);
+                Move::Nudge(midx, from)
+            }
+            1 => {
+                let a = rng.gen_range(0..m);
+                let b = rng.gen_range(0..m);
+                if prob.musicians[a] == prob.musicians[b] {
+                    continue;
+                }
+                state.swap_musicians(prob, a, b);
+                Move::Swap(a, b)
+            }
+            _ => {
+                let midx = rng.gen_range(0..m);
+                let volume = state.volumes()[midx];
+                state.set_volume(midx, if volume > 0.0 { 0.0 } else { 10.0 });
+                Move::Volume(midx, volume)
+            }
+        };
+        iterations += 1;
+        let delta = state.score() - before;
+        if delta >= 0 || rng.gen::<f64>() < (delta as f64 / temp).exp() {
+            accepted += 1;
+            if state.score() > best_score {
+                best = state.solution();
+                best_score = state.score();
+            }
+            continue;
+        }
+        match undo {
+            Move::Nudge(midx, from) => state.move_musician(prob, midx, from),
+            Move::Swap(a, b) => state.swap_musicians(prob, a, b),
+            Move::Volume(midx, volume) => state.set_volume(midx, volume),
+        }
+    }
+    eprintln!(
+        "iterations: {}, accepted: {}, best: {}",
+        iterations, accepted, best_score
+    );
+    Ok(best)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::incremental::test::{random_placements, random_problem};
+
+    #[test]
+    fn test_anneal() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let prob = random_problem(&mut rng, true);
+        let sol = Solution {
+            placements: random_placements(&mut rng, &prob),
+            volumes: vec![1.0; prob.musicians.len()],
+        };
+        let config = AnnealConfig {
+            start_temp: 1e5,
+            end_temp: 1e2,
+            schedule: Schedule::Exponential,
+            time_limit: Duration::from_millis(200),
+            seed: Some(1),
+            step: 5.0,
+        };
+        let result = anneal(&prob, &sol, &config).unwrap();
+        ensure_valid(&prob, &result).unwrap();
+        assert!(score(&prob, &result, true).unwrap() >= score(&prob, &sol, true).unwrap());
+    }
+}
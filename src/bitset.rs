@@ -0,0 +1,162 @@
+use rayon::prelude::*;
+
+// Dense rows × cols matrix of bits, 64 per word. Bits past `cols` in the last word of a row
+// are always kept zero so that rows can be compared word by word.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BitMatrix {
+    cols: usize,
+    words_per_row: usize,
+    data: Vec<u64>,
+}
+
+impl BitMatrix {
+    pub fn new(rows: usize, cols: usize, value: bool) -> BitMatrix {
+        // at least one word per row, so that rows can still be split into chunks
+        let words_per_row = cols.div_ceil(64).max(1);
+        let mut matrix = BitMatrix {
+            cols,
+            words_per_row,
+            data: vec![0; rows * words_per_row],
+        };
+        if value {
+            for row in 0..rows {
+                matrix.fill_row(row, true);
+            }
+        }
+        matrix
+    }
+
+    fn row_words(&self, row: usize) -> &[u64] {
+        &self.data[row * self.words_per_row..(row + 1) * self.words_per_row]
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> bool {
+        debug_assert!(col < self.cols);
+        get_bit(self.row_words(row), col)
+    }
+
+    pub fn set(&mut self, row: usize, col: usize, value: bool) {
+        debug_assert!(col < self.cols);
+        let begin = row * self.words_per_row;
+        set_bit(
+            &mut self.data[begin..begin + self.words_per_row],
+            col,
+            value,
+        );
+    }
+
+    // Mutable views of all rows, for updating different rows from different threads.
+    pub fn par_rows_mut(&mut self) -> impl IndexedParallelIterator<Item = BitRowMut<'_>> {
+        self.data
+            .par_chunks_mut(self.words_per_row)
+            .map(|words| BitRowMut { words })
+    }
+
+    pub fn fill_row(&mut self, row: usize, value: bool) {
+        let cols = self.cols;
+        let words = &mut self.data[row * self.words_per_row..(row + 1) * self.words_per_row];
+        for (w, word) in words.iter_mut().enumerate() {
+            let bits = (cols - 64 * w).min(64);
+            *word = if !value {
+                0
+            } else if bits == 64 {
+                !0
+            } else {
+                (1 << bits) - 1
+            };
+        }
+    }
+
+    pub fn set_row(&mut self, row: usize, values: &[bool]) {
+        assert_eq!(values.len(), self.cols);
+        self.fill_row(row, false);
+        for (col, &value) in values.iter().enumerate() {
+            if value {
+                self.set(row, col, true);
+            }
+        }
+    }
+
+    // Column indices of the set bits in `row`, in increasing order.
+    pub fn ones(&self, row: usize) -> impl Iterator<Item = usize> + '_ {
+        self.row_words(row)
+            .iter()
+            .enumerate()
+            .flat_map(|(w, &word)| {
+                let mut rest = word;
+                std::iter::from_fn(move || {
+                    if rest == 0 {
+                        return None;
+                    }
+                    let bit = rest.trailing_zeros() as usize;
+                    rest &= rest - 1;
+                    Some(64 * w + bit)
+                })
+            })
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.data.len() * std::mem::size_of::<u64>()
+    }
+}
+
+pub struct BitRowMut<'a> {
+    words: &'a mut [u64],
+}
+
+impl BitRowMut<'_> {
+    pub fn get(&self, col: usize) -> bool {
+        get_bit(self.words, col)
+    }
+
+    pub fn set(&mut self, col: usize, value: bool) {
+        set_bit(self.words, col, value);
+    }
+}
+
+fn get_bit(words: &[u64], col: usize) -> bool {
+    words[col / 64] >> (col % 64) & 1 != 0
+}
+
+fn set_bit(words: &mut [u64], col: usize, value: bool) {
+    if value {
+        words[col / 64] |= 1 << (col % 64);
+    } else {
+        words[col / 64] &= !(1 << (col % 64));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::prelude::*;
+    use rand::rngs::SmallRng;
+
+    #[test]
+    fn test_bit_matrix() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        for cols in [1, 63, 64, 65, 200] {
+            let mut matrix = BitMatrix::new(3, cols, true);
+            let mut expected = vec![vec![true; cols]; 3];
+            for _ in 0..500 {
+                let (row, col) = (rng.gen_range(0..3), rng.gen_range(0..cols));
+                let value = rng.gen_bool(0.5);
+                matrix.set(row, col, value);
+                expected[row][col] = value;
+            }
+            matrix.set_row(2, &expected[2]);
+            for (row, values) in expected.iter().enumerate() {
+                let ones: Vec<_> = (0..cols).filter(|&col| values[col]).collect();
+                assert_eq!(matrix.ones(row).collect::<Vec<_>>(), ones);
+                for (col, &value) in values.iter().enumerate() {
+                    assert_eq!(matrix.get(row, col), value);
+                }
+            }
+            let mut other = BitMatrix::new(3, cols, false);
+            for (row, values) in expected.iter().enumerate() {
+                other.set_row(row, values);
+            }
+            assert_eq!(matrix, other);
+        }
+    }
+}
@@ -1,14 +1,45 @@
+use crate::bitset::*;
 use crate::common::*;
 use crate::geometry::*;
 use crate::score::*;
+use rayon::prelude::*;
+
+// 1/distance for the play-together tables. A place at distance 0 from a musician can not be
+// taken while the musician is there, so it gets no term instead of an infinite one.
+fn inv_dist(a: Point, b: Point) -> f64 {
+    let d = (a - b).length();
+    if d > 0.0 {
+        1.0 / d
+    } else {
+        0.0
+    }
+}
 
 pub struct DiffCache {
     places: Vec<Point>,
-    visible: Vec<Vec<bool>>,
+    visible: BitMatrix,
+    // raw impact of each kind at each place over its visible attendees
     impact_diff: Vec<Vec<i64>>,
+    // loss of the placed musicians of each kind when a musician is added at each place
     impact_diff_blocking: Vec<Vec<i64>>,
     pub musician_to_place: Vec<Option<usize>>,
     pub place_to_musician: Vec<Option<usize>>,
+    // placed musicians closer than 10 to each place, which can not be taken while there are any
+    near: Vec<usize>,
+    // impact_diff_blocking of each kind divided by the distance from the place to the blocked
+    // musician; a musician of that kind placed there gets no play-together gain on those lines
+    together_blocking: Vec<Vec<f64>>,
+    // play-together tracking, only maintained for full-division problems
+    full_division: bool,
+    kinds: Vec<u32>,
+    // sum of 1/distance from each place to the placed musicians of each kind
+    together: Vec<Vec<f64>>,
+    // gain of the placed musicians of each kind when a same-kind musician joins at each place
+    together_gain: Vec<Vec<f64>>,
+    // raw impact of each placed musician over its visible attendees
+    placed_impact: Vec<i64>,
+    // volume * placed_impact of each placed musician as counted in together_gain
+    together_weight: Vec<f64>,
 }
 
 impl DiffCache {
@@ -19,77 +50,154 @@ impl DiffCache {
         place_to_musician: &[Option<usize>],
         volumes: &[f64],
     ) -> DiffCache {
-        let mut visible = vec![vec![true; prob.attendees.len()]; places.len()];
-        let mut impact_diff = vec![vec![0; prob.musicians.len()]; places.len()];
-        for (i, &place) in places.iter().enumerate() {
-            for (j, vis) in visible[i].iter_mut().enumerate() {
-                let atd = &prob.attendees[j];
-                *vis = check_pillars(atd, place, &prob.pillars);
-                for &opt_pidx in musician_to_place.iter() {
-                    let Some(pidx) = opt_pidx else { continue; };
-                    if pidx == i {
-                        continue;
-                    }
-                    let another_place = places[pidx];
-                    if is_blocked_by_another(atd, place, another_place) {
-                        *vis = false;
-                        break;
-                    }
-                }
-                if !*vis {
-                    continue;
-                }
-                for (k, &kind) in prob.musicians.iter().enumerate() {
-                    impact_diff[i][k] += impact_raw(atd, kind, place);
-                }
-            }
-        }
-        let mut impact_diff_blocking = vec![vec![0; prob.musicians.len()]; places.len()];
-        for (k, &kind) in prob.musicians.iter().enumerate() {
-            let Some(pidx) = musician_to_place[k] else { continue; };
-            for (j, vis) in visible[pidx].iter().enumerate() {
-                if !*vis {
-                    continue;
-                }
-                let atd = &prob.attendees[j];
-                for (i, &place) in places.iter().enumerate() {
-                    if place_to_musician[i].is_some() {
-                        continue;
-                    }
-                    if is_blocked_by_another(atd, places[pidx], place) {
-                        impact_diff_blocking[i][k] -=
-                            (impact_raw(atd, kind, places[pidx]) as f64 * volumes[k]).ceil() as i64;
-                    }
-                }
-            }
+        let num_kinds = prob.musicians.iter().max().map_or(0, |&k| k as usize + 1);
+        let rows: Vec<_> = places
+            .par_iter()
+            .enumerate()
+            .map(|(i, &place)| {
+                let others = musician_to_place
+                    .iter()
+                    .flatten()
+                    .filter(|&&pidx| pidx != i)
+                    .map(|&pidx| places[pidx]);
+                visible_attendees_from(prob, place, others)
+            })
+            .collect();
+        let mut visible = BitMatrix::new(places.len(), prob.attendees.len(), true);
+        for (i, row) in rows.iter().enumerate() {
+            visible.set_row(i, row);
         }
         let musician_to_place = musician_to_place.to_vec();
         let place_to_musician = place_to_musician.to_vec();
-        DiffCache {
+        let mut cache = DiffCache {
             places: places.to_vec(),
             visible,
-            impact_diff,
-            impact_diff_blocking,
+            impact_diff: vec![vec![0; num_kinds]; places.len()],
+            impact_diff_blocking: Vec::new(),
+            together_blocking: Vec::new(),
             musician_to_place,
             place_to_musician,
+            near: vec![0; places.len()],
+            full_division: is_full_division_scoring(prob),
+            kinds: prob.musicians.clone(),
+            together: vec![vec![0.0; num_kinds]; places.len()],
+            together_gain: vec![vec![0.0; num_kinds]; places.len()],
+            placed_impact: vec![0; prob.musicians.len()],
+            together_weight: vec![0.0; prob.musicians.len()],
+        };
+        cache.impact_diff = (0..places.len())
+            .into_par_iter()
+            .map(|i| cache.impact_diff_row(prob, i))
+            .collect();
+        (cache.impact_diff_blocking, cache.together_blocking) = (0..places.len())
+            .into_par_iter()
+            .map(|i| match cache.place_to_musician[i] {
+                Some(_) => (vec![0; num_kinds], vec![0.0; num_kinds]),
+                None => cache.impact_diff_blocking_row(prob, i, volumes),
+            })
+            .unzip();
+        for k in 0..prob.musicians.len() {
+            let Some(pidx) = cache.musician_to_place[k] else { continue; };
+            cache.update_near(pidx, true);
+            cache.placed_impact[k] = cache.impact_diff[pidx][cache.kinds[k] as usize];
+            cache.update_together(pidx, k, volumes, 1.0);
+        }
+        cache
+    }
+
+    // Approximate heap size of the tables in bytes.
+    pub fn memory_usage(&self) -> usize {
+        fn table<T>(rows: &[Vec<T>]) -> usize {
+            rows.iter()
+                .map(|row| row.capacity() * std::mem::size_of::<T>())
+                .sum()
+        }
+        self.visible.memory_usage()
+            + table(&self.impact_diff)
+            + table(&self.impact_diff_blocking)
+            + table(&self.together_blocking)
+            + table(&self.together)
+            + table(&self.together_gain)
+            + self.near.capacity() * std::mem::size_of::<usize>()
+    }
+
+    // Counts a musician added (or removed) at `pidx` in `near` of the places around it.
+    fn update_near(&mut self, pidx: usize, add: bool) {
+        let place_self = self.places[pidx];
+        for (i, &place) in self.places.iter().enumerate() {
+            if i != pidx && (place - place_self).norm() < 100.0 {
+                if add {
+                    self.near[i] += 1;
+                } else {
+                    self.near[i] -= 1;
+                }
+            }
+        }
+    }
+
+    // Adds (`sign` = 1) or removes (`sign` = -1) musician `midx` at `pidx` from the
+    // play-together tables.
+    fn update_together(&mut self, pidx: usize, midx: usize, volumes: &[f64], sign: f64) {
+        if !self.full_division {
+            return;
+        }
+        if sign > 0.0 {
+            self.together_weight[midx] = volumes[midx] * self.placed_impact[midx] as f64;
+        }
+        let kind = self.kinds[midx] as usize;
+        let place_self = self.places[pidx];
+        for (i, &place) in self.places.iter().enumerate() {
+            if i == pidx {
+                continue;
+            }
+            let inv_dist = inv_dist(place, place_self);
+            self.together[i][kind] += sign * inv_dist;
+            self.together_gain[i][kind] += sign * self.together_weight[midx] * inv_dist;
+        }
+    }
+
+    // Brings together_gain in line with a change of placed_impact of musician `midx`.
+    fn refresh_together_weight(&mut self, midx: usize, volumes: &[f64]) {
+        if !self.full_division {
+            return;
+        }
+        let Some(pidx) = self.musician_to_place[midx] else {
+            return;
+        };
+        let weight = volumes[midx] * self.placed_impact[midx] as f64;
+        let delta = weight - self.together_weight[midx];
+        if delta == 0.0 {
+            return;
+        }
+        self.together_weight[midx] = weight;
+        let kind = self.kinds[midx] as usize;
+        let place_self = self.places[pidx];
+        for (i, &place) in self.places.iter().enumerate() {
+            if i != pidx {
+                self.together_gain[i][kind] += delta * inv_dist(place, place_self);
+            }
         }
     }
 
     pub fn find_best_matching(&self) -> (usize, usize, i64, f64) {
+        if self.full_division {
+            return self.find_best_matching_together();
+        }
+        let free = self.free_musician_of_kind();
         self.impact_diff
             .iter()
             .enumerate()
-            .filter(|(i, _)| self.place_to_musician[*i].is_none())
+            .filter(|(i, _)| self.place_to_musician[*i].is_none() && self.near[*i] == 0)
             .map(|(i, impacts)| {
-                let (j, impact) = impacts
+                let (impact, j) = impacts
                     .iter()
-                    .enumerate()
-                    .filter(|(j, _)| self.musician_to_place[*j].is_none())
-                    .max_by_key(|(_, &impact)| impact)
+                    .zip(free.iter())
+                    .filter_map(|(&impact, &j)| Some((impact, j?)))
+                    .max()
                     .unwrap();
                 let penalty: i64 = self.impact_diff_blocking[i].iter().sum();
-                if *impact >= 0 {
-                    (i, j, 10 * *impact + penalty, 10.0)
+                if impact >= 0 {
+                    (i, j, 10 * impact + penalty, 10.0)
                 } else {
                     (i, j, penalty, 0.0)
                 }
@@ -98,67 +206,139 @@ impl DiffCache {
             .unwrap()
     }
 
-    fn update_direct(&mut self, prob: &Problem, pidx: usize, midx: usize, volumes: &[f64]) -> i64 {
-        let place_self = self.places[pidx];
-        for (i, &place_another) in self.places.iter().enumerate() {
-            if self.place_to_musician[i].is_some() {
-                continue;
+    // Musicians of the same kind are interchangeable while unassigned, so only the last free
+    // one of each kind is considered, which is also the one picked on ties among all of them.
+    fn free_musician_of_kind(&self) -> Vec<Option<usize>> {
+        let mut free = vec![None; self.impact_diff.first().map_or(0, |row| row.len())];
+        for (k, &kind) in self.kinds.iter().enumerate() {
+            if self.musician_to_place[k].is_none() {
+                free[kind as usize] = Some(k);
             }
-            let block_area_self = Circle {
-                c: place_self,
-                r: 5.0,
-            };
-            for (j, atd) in prob.attendees.iter().enumerate() {
-                let segment_another = Line {
-                    p1: place_another,
-                    p2: atd.place(),
-                };
-                if is_cross_line_circle(segment_another, block_area_self) && self.visible[i][j] {
-                    self.visible[i][j] = false;
-                    for (k, &kind) in prob.musicians.iter().enumerate() {
-                        self.impact_diff[i][k] -= impact_raw(atd, kind, place_another);
-                    }
-                }
+        }
+        free
+    }
+
+    // Same as find_best_matching, but every impact is scaled by the play-together scalar and
+    // the gain of same-kind musicians already on the stage is included.
+    fn find_best_matching_together(&self) -> (usize, usize, i64, f64) {
+        // Blocking losses are only known per kind, so they are scaled by the mean scalar of the
+        // kind instead of the scalar of each blocked musician. The prediction is off by at most
+        // the loss of each blocked musician times the spread of the scalars of its kind, see
+        // test_predicted_gain; exact losses would need a table of places by musicians.
+        let mut scalars = vec![(0.0, 0); self.together.first().map_or(0, |row| row.len())];
+        for (opt_pidx, &kind) in self.musician_to_place.iter().zip(self.kinds.iter()) {
+            if let Some(pidx) = opt_pidx {
+                let (sum, count) = &mut scalars[kind as usize];
+                *sum += 1.0 + self.together[*pidx][kind as usize];
+                *count += 1;
             }
         }
-        (self.impact_diff[pidx][midx] as f64 * volumes[midx]).ceil() as i64
+        let scalars: Vec<_> = scalars
+            .into_iter()
+            .map(|(sum, count)| if count > 0 { sum / count as f64 } else { 1.0 })
+            .collect();
+        let free = self.free_musician_of_kind();
+        self.impact_diff
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.place_to_musician[*i].is_none() && self.near[*i] == 0)
+            .map(|(i, impacts)| {
+                let (j, gain, volume) = impacts
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(kind, &impact)| {
+                        let j = free[kind]?;
+                        let own = impact as f64 * (1.0 + self.together[i][kind]);
+                        let volume = if own >= 0.0 { 10.0 } else { 0.0 };
+                        let together =
+                            self.together_gain[i][kind] + self.together_blocking[i][kind];
+                        Some((j, volume * own + together, volume))
+                    })
+                    .max_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)))
+                    .unwrap();
+                let penalty: f64 = self.impact_diff_blocking[i]
+                    .iter()
+                    .zip(scalars.iter())
+                    .map(|(&blocking, scalar)| blocking as f64 * scalar)
+                    .sum();
+                (i, j, (gain + penalty).ceil() as i64, volume)
+            })
+            .max_by_key(|(_, _, v, _)| *v)
+            .unwrap()
     }
 
+    fn update_direct(&mut self, prob: &Problem, pidx: usize, midx: usize, volumes: &[f64]) -> i64 {
+        let block_area_self = Circle {
+            c: self.places[pidx],
+            r: 5.0,
+        };
+        let places = &self.places;
+        let place_to_musician = &self.place_to_musician;
+        self.visible
+            .par_rows_mut()
+            .zip(self.impact_diff.par_iter_mut())
+            .enumerate()
+            .filter(|(i, _)| place_to_musician[*i].is_none())
+            .for_each(|(i, (mut visible, impacts))| {
+                let place_another = places[i];
+                for (j, atd) in prob.attendees.iter().enumerate() {
+                    let segment_another = Line {
+                        p1: place_another,
+                        p2: atd.place(),
+                    };
+                    if is_cross_line_circle(segment_another, block_area_self) && visible.get(j) {
+                        visible.set(j, false);
+                        for (kind, impact) in impacts.iter_mut().enumerate() {
+                            *impact -= impact_raw(atd, kind as u32, place_another);
+                        }
+                    }
+                }
+            });
+        let kind = self.kinds[midx] as usize;
+        (self.impact_diff[pidx][kind] as f64 * volumes[midx]).ceil() as i64
+    }
+
+    // Attendees of each placed musician whose line becomes blocked by the new one at `pidx`.
     fn update_block_dec(
         &mut self,
         prob: &Problem,
         pidx: usize,
-        i: usize,
-        place_another: Point,
-        midx_another: usize,
         volumes: &[f64],
-    ) {
-        let place_self = self.places[pidx];
+    ) -> Vec<(usize, Vec<usize>)> {
         let block_area_self = Circle {
-            c: place_self,
+            c: self.places[pidx],
             r: 5.0,
         };
-        for (j, atd) in prob.attendees.iter().enumerate() {
-            let segment_another = Line {
-                p1: place_another,
-                p2: atd.place(),
-            };
-            if is_cross_line_circle(segment_another, block_area_self) && self.visible[i][j] {
-                self.visible[i][j] = false;
-                let kind = prob.musicians[midx_another];
-                for (ii, &place2) in self.places.iter().enumerate() {
-                    if self.place_to_musician[ii].is_some() {
-                        continue;
-                    }
-                    let block_area_2 = Circle { c: place2, r: 5.0 };
-                    if is_cross_line_circle(segment_another, block_area_2) {
-                        self.impact_diff_blocking[ii][midx_another] +=
-                            (impact_raw(atd, kind, place_another) as f64 * volumes[midx_another])
-                                .ceil() as i64;
-                    }
-                }
+        let hidden: Vec<_> = (0..self.places.len())
+            .into_par_iter()
+            .filter(|&i| i != pidx && self.place_to_musician[i].is_some())
+            .map(|i| {
+                let place_another = self.places[i];
+                let attendees: Vec<_> = self
+                    .visible
+                    .ones(i)
+                    .filter(|&j| {
+                        let segment_another = Line {
+                            p1: place_another,
+                            p2: prob.attendees[j].place(),
+                        };
+                        is_cross_line_circle(segment_another, block_area_self)
+                    })
+                    .collect();
+                (i, attendees)
+            })
+            .collect();
+        for (i, attendees) in &hidden {
+            let midx_another = self.place_to_musician[*i].unwrap();
+            let kind = self.kinds[midx_another];
+            for &j in attendees {
+                self.visible.set(*i, j, false);
+                self.placed_impact[midx_another] -=
+                    impact_raw(&prob.attendees[j], kind, self.places[*i]);
             }
+            self.refresh_together_weight(midx_another, volumes);
         }
+        hidden
     }
 
     fn update_block_inc(
@@ -166,63 +346,631 @@ impl DiffCache {
         prob: &Problem,
         pidx: usize,
         midx: usize,
-        i: usize,
-        place_another: Point,
+        hidden: &[(usize, Vec<usize>)],
         volumes: &[f64],
     ) {
         let place_self = self.places[pidx];
-        let kind_self = prob.musicians[midx];
-        let block_area_another = Circle {
-            c: place_another,
-            r: 5.0,
-        };
-        for (j, atd) in prob.attendees.iter().enumerate() {
-            let segment_self = Line {
-                p1: place_self,
-                p2: atd.place(),
-            };
-            if is_cross_line_circle(segment_self, block_area_another) && self.visible[pidx][j] {
-                self.impact_diff_blocking[i][midx] -=
-                    (impact_raw(atd, kind_self, place_self) as f64 * volumes[midx]).ceil() as i64;
+        let kind_self = self.kinds[midx];
+        let visible_self: Vec<_> = self.visible.ones(pidx).collect();
+        let places = &self.places;
+        let place_to_musician = &self.place_to_musician;
+        let kinds = &self.kinds;
+        self.impact_diff_blocking
+            .par_iter_mut()
+            .zip(self.together_blocking.par_iter_mut())
+            .enumerate()
+            .filter(|(i, _)| place_to_musician[*i].is_none())
+            .for_each(|(i, (row, together_row))| {
+                let block_area_another = Circle {
+                    c: places[i],
+                    r: 5.0,
+                };
+                // lines now hidden by the new musician are no longer lost by placing here
+                for (i_hidden, attendees) in hidden {
+                    let midx_another = place_to_musician[*i_hidden].unwrap();
+                    let place_another = places[*i_hidden];
+                    for &j in attendees {
+                        let atd = &prob.attendees[j];
+                        let segment_another = Line {
+                            p1: place_another,
+                            p2: atd.place(),
+                        };
+                        if is_cross_line_circle(segment_another, block_area_another) {
+                            let kind = kinds[midx_another] as usize;
+                            let loss = (impact_raw(atd, kinds[midx_another], place_another) as f64
+                                * volumes[midx_another])
+                                .ceil() as i64;
+                            row[kind] += loss;
+                            together_row[kind] += loss as f64 * inv_dist(places[i], place_another);
+                        }
+                    }
+                }
+                // lines of the new musician that this place would block
+                for &j in &visible_self {
+                    let atd = &prob.attendees[j];
+                    let segment_self = Line {
+                        p1: place_self,
+                        p2: atd.place(),
+                    };
+                    if is_cross_line_circle(segment_self, block_area_another) {
+                        let loss = (impact_raw(atd, kind_self, place_self) as f64 * volumes[midx])
+                            .ceil() as i64;
+                        row[kind_self as usize] -= loss;
+                        together_row[kind_self as usize] -=
+                            loss as f64 * inv_dist(places[i], place_self);
+                    }
+                }
+            });
+    }
+
+    fn update_block(&mut self, prob: &Problem, pidx: usize, midx: usize, volumes: &[f64]) -> i64 {
+        let diff = self.impact_diff_blocking[pidx].iter().sum();
+        let hidden = self.update_block_dec(prob, pidx, volumes);
+        self.update_block_inc(prob, pidx, midx, &hidden, volumes);
+        diff
+    }
+
+    pub fn add_matching(
+        &mut self,
+        prob: &Problem,
+        pidx: usize,
+        midx: usize,
+        volumes: &[f64],
+    ) -> i64 {
+        assert!(self.musician_to_place[midx].is_none());
+        assert!(self.place_to_musician[pidx].is_none());
+        assert_eq!(self.near[pidx], 0);
+        self.musician_to_place[midx] = Some(pidx);
+        self.place_to_musician[pidx] = Some(midx);
+        self.update_near(pidx, true);
+        self.placed_impact[midx] = self.impact_diff[pidx][self.kinds[midx] as usize];
+        let diff = self.update_direct(prob, pidx, midx, volumes)
+            + self.update_block(prob, pidx, midx, volumes);
+        self.update_together(pidx, midx, volumes, 1.0);
+        diff
+    }
+
+    fn is_visible(&self, prob: &Problem, i: usize, j: usize) -> bool {
+        let atd = &prob.attendees[j];
+        let place = self.places[i];
+        if !check_pillars(atd, place, &prob.pillars) {
+            return false;
+        }
+        self.musician_to_place
+            .iter()
+            .flatten()
+            .all(|&pidx| pidx == i || !is_blocked_by_another(atd, place, self.places[pidx]))
+    }
+
+    fn impact_diff_row(&self, prob: &Problem, i: usize) -> Vec<i64> {
+        let place = self.places[i];
+        let mut row = vec![0; self.impact_diff[i].len()];
+        for j in self.visible.ones(i) {
+            let atd = &prob.attendees[j];
+            for (kind, impact) in row.iter_mut().enumerate() {
+                *impact += impact_raw(atd, kind as u32, place);
             }
         }
+        row
     }
 
-    fn update_block(&mut self, prob: &Problem, pidx: usize, midx: usize, volumes: &[f64]) -> i64 {
-        let mut diff = 0;
-        for (k, opt_place) in self.musician_to_place.iter().enumerate() {
-            if let Some(_) = opt_place {
-                diff += self.impact_diff_blocking[pidx][k];
+    // Rows of impact_diff_blocking and together_blocking for place `i`.
+    fn impact_diff_blocking_row(
+        &self,
+        prob: &Problem,
+        i: usize,
+        volumes: &[f64],
+    ) -> (Vec<i64>, Vec<f64>) {
+        let place = self.places[i];
+        let mut row = vec![0; self.impact_diff[i].len()];
+        let mut together_row = vec![0.0; row.len()];
+        for (k, &kind) in prob.musicians.iter().enumerate() {
+            let Some(pidx) = self.musician_to_place[k] else { continue; };
+            let mut loss = 0;
+            for (j, atd) in prob.attendees.iter().enumerate() {
+                if self.visible.get(pidx, j) && is_blocked_by_another(atd, self.places[pidx], place)
+                {
+                    loss += (impact_raw(atd, kind, self.places[pidx]) as f64 * volumes[k]).ceil()
+                        as i64;
+                }
             }
+            row[kind as usize] -= loss;
+            together_row[kind as usize] -= loss as f64 * inv_dist(place, self.places[pidx]);
         }
-        let num = self.places.len();
-        for i in 0..num {
+        (row, together_row)
+    }
+
+    // Takes the lines of musician `midx` at `pidx` out of the blocking losses of the free places.
+    fn remove_blocking_loss(&mut self, prob: &Problem, pidx: usize, midx: usize, volumes: &[f64]) {
+        let place_self = self.places[pidx];
+        let kind = self.kinds[midx];
+        let visible_self: Vec<_> = self.visible.ones(pidx).collect();
+        let places = &self.places;
+        let place_to_musician = &self.place_to_musician;
+        self.impact_diff_blocking
+            .par_iter_mut()
+            .zip(self.together_blocking.par_iter_mut())
+            .enumerate()
+            .filter(|(i, _)| *i != pidx && place_to_musician[*i].is_none())
+            .for_each(|(i, (row, together_row))| {
+                let mut loss = 0;
+                for &j in &visible_self {
+                    let atd = &prob.attendees[j];
+                    if is_blocked_by_another(atd, place_self, places[i]) {
+                        loss += (impact_raw(atd, kind, place_self) as f64 * volumes[midx]).ceil()
+                            as i64;
+                    }
+                }
+                row[kind as usize] += loss;
+                together_row[kind as usize] += loss as f64 * inv_dist(places[i], place_self);
+            });
+    }
+
+    pub fn remove_matching(&mut self, prob: &Problem, pidx: usize, volumes: &[f64]) -> usize {
+        let midx = self.place_to_musician[pidx].unwrap();
+        self.update_together(pidx, midx, volumes, -1.0);
+        self.remove_blocking_loss(prob, pidx, midx, volumes);
+        self.musician_to_place[midx] = None;
+        self.place_to_musician[pidx] = None;
+        self.update_near(pidx, false);
+        self.placed_impact[midx] = 0;
+        let block_area_self = Circle {
+            c: self.places[pidx],
+            r: 5.0,
+        };
+        for i in 0..self.places.len() {
             if i == pidx {
                 continue;
             }
-            match self.place_to_musician[i] {
-                Some(midx_another) => {
-                    self.update_block_dec(prob, pidx, i, self.places[i], midx_another, volumes);
+            let place_another = self.places[i];
+            for (j, atd) in prob.attendees.iter().enumerate() {
+                if self.visible.get(i, j) {
+                    continue;
                 }
-                None => {
-                    self.update_block_inc(prob, pidx, midx, i, self.places[i], volumes);
+                let segment_another = Line {
+                    p1: place_another,
+                    p2: atd.place(),
+                };
+                if !is_cross_line_circle(segment_another, block_area_self)
+                    || !self.is_visible(prob, i, j)
+                {
+                    continue;
+                }
+                self.visible.set(i, j, true);
+                match self.place_to_musician[i] {
+                    Some(midx_another) => {
+                        let kind = prob.musicians[midx_another];
+                        self.placed_impact[midx_another] += impact_raw(atd, kind, place_another);
+                        for (ii, &place2) in self.places.iter().enumerate() {
+                            if self.place_to_musician[ii].is_some() {
+                                continue;
+                            }
+                            let block_area_2 = Circle { c: place2, r: 5.0 };
+                            if is_cross_line_circle(segment_another, block_area_2) {
+                                let loss = (impact_raw(atd, kind, place_another) as f64
+                                    * volumes[midx_another])
+                                    .ceil() as i64;
+                                self.impact_diff_blocking[ii][kind as usize] -= loss;
+                                self.together_blocking[ii][kind as usize] -=
+                                    loss as f64 * inv_dist(place2, place_another);
+                            }
+                        }
+                    }
+                    None => {
+                        for (kind, impact) in self.impact_diff[i].iter_mut().enumerate() {
+                            *impact += impact_raw(atd, kind as u32, place_another);
+                        }
+                    }
                 }
             }
         }
-        diff
+        // lines hidden only by the removed musician count again for play-together
+        for midx_another in 0..self.kinds.len() {
+            self.refresh_together_weight(midx_another, volumes);
+        }
+        // rows of occupied places are not maintained, so rebuild them for the freed place
+        self.impact_diff[pidx] = self.impact_diff_row(prob, pidx);
+        (
+            self.impact_diff_blocking[pidx],
+            self.together_blocking[pidx],
+        ) = self.impact_diff_blocking_row(prob, pidx, volumes);
+        midx
     }
 
-    pub fn add_matching(
+    pub fn move_matching(
         &mut self,
         prob: &Problem,
-        pidx: usize,
         midx: usize,
+        from: usize,
+        to: usize,
         volumes: &[f64],
     ) -> i64 {
-        assert!(self.musician_to_place[midx].is_none());
-        assert!(self.place_to_musician[pidx].is_none());
-        self.musician_to_place[midx] = Some(pidx);
-        self.place_to_musician[pidx] = Some(midx);
-        self.update_direct(prob, pidx, midx, volumes) + self.update_block(prob, pidx, midx, volumes)
+        assert_eq!(self.place_to_musician[from], Some(midx));
+        self.remove_matching(prob, from, volumes);
+        self.add_matching(prob, to, midx, volumes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::prelude::*;
+    use rand::rngs::SmallRng;
+
+    fn test_problem(rng: &mut SmallRng) -> (Problem, Vec<Point>) {
+        let attendees = (0..40)
+            .map(|_| Attendee {
+                x: rng.gen_range(0.0..200.0),
+                y: rng.gen_range(0.0..60.0),
+                tastes: (0..2).map(|_| rng.gen_range(-1000.0..1000.0)).collect(),
+            })
+            .collect();
+        let prob = Problem {
+            room_width: 200.0,
+            room_height: 200.0,
+            stage_width: 100.0,
+            stage_height: 60.0,
+            stage_bottom_left: vec![50.0, 100.0],
+            musicians: vec![0, 1, 0, 1, 0, 1],
+            attendees,
+            pillars: vec![Pillar {
+                center: (100.0, 80.0),
+                radius: 5.0,
+            }],
+        };
+        let places: Vec<_> = (0..4)
+            .flat_map(|row| {
+                (0..9).map(move |col| Point {
+                    x: 60.0 + 10.0 * col as f64,
+                    y: 110.0 + 10.0 * row as f64,
+                })
+            })
+            .collect();
+        (prob, places)
+    }
+
+    #[test]
+    fn test_remove_matching() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let (prob, places) = test_problem(&mut rng);
+        let m = prob.musicians.len();
+        let volumes: Vec<_> = (0..m).map(|k| k as f64).collect();
+        let mut cache = DiffCache::new(
+            &prob,
+            &places,
+            &vec![None; m],
+            &vec![None; places.len()],
+            &volumes,
+        );
+        for _ in 0..100 {
+            let midx = rng.gen_range(0..m);
+            let free: Vec<_> = (0..places.len())
+                .filter(|&i| cache.place_to_musician[i].is_none())
+                .collect();
+            let to = *free.choose(&mut rng).unwrap();
+            match cache.musician_to_place[midx] {
+                Some(from) if rng.gen_bool(0.5) => {
+                    cache.move_matching(&prob, midx, from, to, &volumes);
+                }
+                Some(from) => {
+                    cache.remove_matching(&prob, from, &volumes);
+                }
+                None => {
+                    cache.add_matching(&prob, to, midx, &volumes);
+                }
+            }
+            let fresh = DiffCache::new(
+                &prob,
+                &places,
+                &cache.musician_to_place,
+                &cache.place_to_musician,
+                &volumes,
+            );
+            assert_fresh_tables(&prob, &cache, &volumes);
+            assert_eq!(cache.visible, fresh.visible);
+            for i in 0..places.len() {
+                if cache.place_to_musician[i].is_none() {
+                    assert_eq!(cache.impact_diff[i], fresh.impact_diff[i]);
+                    assert_eq!(cache.impact_diff_blocking[i], fresh.impact_diff_blocking[i]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_near_places() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let (prob, _) = test_problem(&mut rng);
+        let m = prob.musicians.len();
+        // places 5 apart, so that most pairs can not be taken together
+        let places: Vec<_> = (0..9)
+            .flat_map(|row| {
+                (0..17).map(move |col| Point {
+                    x: 60.0 + 5.0 * col as f64,
+                    y: 110.0 + 5.0 * row as f64,
+                })
+            })
+            .collect();
+        let mut volumes = vec![10.0; m];
+        let mut cache = DiffCache::new(
+            &prob,
+            &places,
+            &vec![None; m],
+            &vec![None; places.len()],
+            &volumes,
+        );
+        for step in 0..30 {
+            if step >= m {
+                let midx = rng.gen_range(0..m);
+                let pidx = cache.musician_to_place[midx].unwrap();
+                cache.remove_matching(&prob, pidx, &volumes);
+            }
+            let (i, j, _, v) = cache.find_best_matching();
+            volumes[j] = v;
+            cache.add_matching(&prob, i, j, &volumes);
+            let placed: Vec<_> = cache.musician_to_place.iter().flatten().collect();
+            for (a, &&pa) in placed.iter().enumerate() {
+                for &&pb in &placed[a + 1..] {
+                    assert!((places[pa] - places[pb]).norm() >= 100.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_duplicate_places() {
+        let mut rng = SmallRng::seed_from_u64(4);
+        let (prob, mut places) = test_problem(&mut rng);
+        let m = prob.musicians.len();
+        places.push(places[0]);
+        let volumes = vec![10.0; m];
+        let mut cache = DiffCache::new(
+            &prob,
+            &places,
+            &vec![None; m],
+            &vec![None; places.len()],
+            &volumes,
+        );
+        cache.add_matching(&prob, 0, 0, &volumes);
+        cache.add_matching(&prob, 1, 2, &volumes);
+        cache.remove_matching(&prob, 0, &volumes);
+        cache.add_matching(&prob, places.len() - 1, 0, &volumes);
+        cache.add_matching(&prob, 5, 4, &volumes);
+        for table in [
+            &cache.together,
+            &cache.together_gain,
+            &cache.together_blocking,
+        ] {
+            assert!(table.iter().flatten().all(|v| v.is_finite()));
+        }
+        let (i, _, _, _) = cache.find_best_matching();
+        assert_ne!(i, 0);
+    }
+
+    // Score of the placed musicians alone.
+    fn partial_score(prob: &Problem, cache: &DiffCache, volumes: &[f64]) -> i64 {
+        let placed: Vec<_> = (0..prob.musicians.len())
+            .filter(|&k| cache.musician_to_place[k].is_some())
+            .collect();
+        let mut sub = prob.clone();
+        sub.musicians = placed.iter().map(|&k| prob.musicians[k]).collect();
+        let sol = Solution {
+            placements: placed
+                .iter()
+                .map(|&k| cache.places[cache.musician_to_place[k].unwrap()])
+                .collect(),
+            volumes: placed.iter().map(|&k| volumes[k]).collect(),
+        };
+        score(&sub, &sol, true).unwrap()
+    }
+
+    // Loss of each placed musician when someone stands at place `i`.
+    fn blocked_losses(
+        prob: &Problem,
+        cache: &DiffCache,
+        volumes: &[f64],
+        i: usize,
+    ) -> Vec<(usize, i64)> {
+        (0..prob.musicians.len())
+            .filter_map(|k| {
+                let pidx = cache.musician_to_place[k]?;
+                let place = cache.places[pidx];
+                let loss = prob
+                    .attendees
+                    .iter()
+                    .enumerate()
+                    .filter(|&(j, atd)| {
+                        cache.visible.get(pidx, j)
+                            && is_blocked_by_another(atd, place, cache.places[i])
+                    })
+                    .map(|(_, atd)| {
+                        (impact_raw(atd, prob.musicians[k], place) as f64 * volumes[k]).ceil()
+                            as i64
+                    })
+                    .sum();
+                Some((k, loss))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_predicted_gain() {
+        for seed in 0..5 {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            let (mut prob, places) = test_problem(&mut rng);
+            for attendee in prob.attendees.iter_mut() {
+                attendee.tastes = (0..3).map(|_| rng.gen_range(-1000.0..1000.0)).collect();
+            }
+            prob.musicians = (0..9).map(|_| rng.gen_range(0..3)).collect();
+            let m = prob.musicians.len();
+            let mut volumes = vec![10.0; m];
+            let mut cache = DiffCache::new(
+                &prob,
+                &places,
+                &vec![None; m],
+                &vec![None; places.len()],
+                &volumes,
+            );
+            for _ in 0..30 {
+                // random changes, so that lines of placed musicians get hidden and revealed
+                let midx = rng.gen_range(0..m);
+                match cache.musician_to_place[midx] {
+                    Some(pidx) => {
+                        cache.remove_matching(&prob, pidx, &volumes);
+                    }
+                    None => {
+                        let free: Vec<_> = (0..places.len())
+                            .filter(|&i| cache.place_to_musician[i].is_none())
+                            .collect();
+                        cache.add_matching(&prob, *free.choose(&mut rng).unwrap(), midx, &volumes);
+                    }
+                }
+                if cache.musician_to_place.iter().all(|e| e.is_some()) {
+                    continue;
+                }
+                let before = partial_score(&prob, &cache, &volumes);
+                let (i, j, predicted, v) = cache.find_best_matching();
+                // the loss of each blocked musician is scaled by the mean scalar of its kind
+                // instead of its own, which is off by at most the spread of those scalars
+                let scalar = |k: usize| {
+                    let kind = prob.musicians[k] as usize;
+                    1.0 + cache.together[cache.musician_to_place[k].unwrap()][kind]
+                };
+                let approximation: f64 = blocked_losses(&prob, &cache, &volumes, i)
+                    .into_iter()
+                    .map(|(k, loss)| {
+                        let same_kind = (0..m).filter(|&l| {
+                            prob.musicians[l] == prob.musicians[k]
+                                && cache.musician_to_place[l].is_some()
+                        });
+                        let (low, high) = same_kind.fold((f64::MAX, f64::MIN), |(low, high), l| {
+                            (low.min(scalar(l)), high.max(scalar(l)))
+                        });
+                        loss.abs() as f64 * (high - low)
+                    })
+                    .sum();
+                volumes[j] = v;
+                cache.add_matching(&prob, i, j, &volumes);
+                let actual = partial_score(&prob, &cache, &volumes) - before;
+                // each term of the score is rounded up on its own
+                let terms = (m * prob.attendees.len()) as f64;
+                assert!(
+                    ((predicted - actual) as f64).abs() <= terms + approximation,
+                    "predicted {} actual {} approximation {}",
+                    predicted,
+                    actual,
+                    approximation
+                );
+                cache.remove_matching(&prob, i, &volumes);
+                volumes[j] = 10.0;
+            }
+        }
+    }
+
+    fn sees(prob: &Problem, cache: &DiffCache, i: usize, atd: &Attendee) -> bool {
+        let line = Line {
+            p1: cache.places[i],
+            p2: atd.place(),
+        };
+        let pillars = prob.pillars.iter().map(|pillar| Circle {
+            c: pillar.c(),
+            r: pillar.radius,
+        });
+        let musicians = cache
+            .musician_to_place
+            .iter()
+            .flatten()
+            .filter(|&&pidx| pidx != i)
+            .map(|&pidx| Circle {
+                c: cache.places[pidx],
+                r: 5.0,
+            });
+        !pillars
+            .chain(musicians)
+            .any(|circle| is_cross_line_circle(line, circle))
+    }
+
+    // Checks the tables against segment tests done from scratch for every place.
+    fn assert_fresh_tables(prob: &Problem, cache: &DiffCache, volumes: &[f64]) {
+        let num_kinds = cache.impact_diff[0].len();
+        for i in 0..cache.places.len() {
+            for (j, atd) in prob.attendees.iter().enumerate() {
+                assert_eq!(cache.visible.get(i, j), sees(prob, cache, i, atd));
+            }
+            if cache.place_to_musician[i].is_some() {
+                continue;
+            }
+            let mut impacts = vec![0; num_kinds];
+            let mut blocking = vec![0; num_kinds];
+            let mut together = vec![0.0; num_kinds];
+            for atd in prob.attendees.iter() {
+                if sees(prob, cache, i, atd) {
+                    for (kind, impact) in impacts.iter_mut().enumerate() {
+                        *impact += impact_raw(atd, kind as u32, cache.places[i]);
+                    }
+                }
+                for (k, &kind) in prob.musicians.iter().enumerate() {
+                    let Some(pidx) = cache.musician_to_place[k] else { continue; };
+                    let line = Line {
+                        p1: cache.places[pidx],
+                        p2: atd.place(),
+                    };
+                    let circle = Circle {
+                        c: cache.places[i],
+                        r: 5.0,
+                    };
+                    if sees(prob, cache, pidx, atd) && is_cross_line_circle(line, circle) {
+                        let loss = (impact_raw(atd, kind, cache.places[pidx]) as f64 * volumes[k])
+                            .ceil() as i64;
+                        blocking[kind as usize] -= loss;
+                        together[kind as usize] -=
+                            loss as f64 * inv_dist(cache.places[i], cache.places[pidx]);
+                    }
+                }
+            }
+            assert_eq!(cache.impact_diff[i], impacts);
+            assert_eq!(cache.impact_diff_blocking[i], blocking);
+            for (&actual, expected) in cache.together_blocking[i].iter().zip(together) {
+                assert!((actual - expected).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_build() {
+        let mut rng = SmallRng::seed_from_u64(2);
+        let (prob, places) = test_problem(&mut rng);
+        let m = prob.musicians.len();
+        let build = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                let mut volumes = vec![10.0; m];
+                let mut cache = DiffCache::new(
+                    &prob,
+                    &places,
+                    &vec![None; m],
+                    &vec![None; places.len()],
+                    &volumes,
+                );
+                assert_fresh_tables(&prob, &cache, &volumes);
+                let mut diffs = Vec::new();
+                for _ in 0..m {
+                    let (i, j, _, v) = cache.find_best_matching();
+                    volumes[j] = v;
+                    diffs.push(cache.add_matching(&prob, i, j, &volumes));
+                    assert_fresh_tables(&prob, &cache, &volumes);
+                }
+                (cache, diffs)
+            })
+        };
+        let (serial, serial_diffs) = build(1);
+        let (parallel, parallel_diffs) = build(4);
+        assert_eq!(serial_diffs, parallel_diffs);
+        assert_eq!(serial.visible, parallel.visible);
+        assert_eq!(serial.impact_diff, parallel.impact_diff);
+        assert_eq!(serial.impact_diff_blocking, parallel.impact_diff_blocking);
     }
 }
@@ -0,0 +1,275 @@
+use crate::common::*;
+use anyhow::Result;
+use rand::prelude::*;
+use rand::rngs::SmallRng;
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// State of a solver run written next to its output, enough to resume it with `Climb --resume`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Checkpoint {
+    pub score: i64,
+    // where the best solution was found, only for display
+    pub iteration: usize,
+    pub seed: Option<u64>,
+    // iterations run so far by each placement mode
+    #[serde(default)]
+    pub completed: Vec<usize>,
+    // values drawn so far from the random stream of each placement mode
+    #[serde(default)]
+    pub draws: Vec<u64>,
+    pub solution: Solution,
+}
+
+impl Checkpoint {
+    pub fn load_from_file(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    fn save_to_file(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer(BufWriter::new(file), self)?;
+        Ok(())
+    }
+}
+
+struct CheckpointState {
+    seed: Option<u64>,
+    completed: Vec<usize>,
+    draws: Vec<u64>,
+    best: Option<Checkpoint>,
+    dirty: bool,
+    progress_dirty: bool,
+    last_save: Instant,
+}
+
+// Keeps the best solution reported by a solver and writes it to the output path at most once
+// per interval, and whenever the run is asked to stop by SIGINT or SIGTERM.
+// A second signal terminates the process right away. Solve and Climb leave writing their output
+// to it, so a solution found earlier is never replaced by a worse one.
+pub struct Checkpointer {
+    output: PathBuf,
+    interval: Duration,
+    interrupted: Arc<AtomicBool>,
+    state: Mutex<CheckpointState>,
+}
+
+impl Checkpointer {
+    pub fn new(output: &Path, interval: Duration) -> Result<Checkpointer> {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        for signal in [SIGINT, SIGTERM] {
+            flag::register_conditional_shutdown(signal, 1, interrupted.clone())?;
+            flag::register(signal, interrupted.clone())?;
+        }
+        Ok(Checkpointer {
+            output: output.to_path_buf(),
+            interval,
+            interrupted,
+            state: Mutex::new(CheckpointState {
+                seed: None,
+                completed: Vec::new(),
+                draws: Vec::new(),
+                best: None,
+                dirty: false,
+                progress_dirty: false,
+                last_save: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn checkpoint_path(&self) -> PathBuf {
+        self.output.with_extension("checkpoint.json")
+    }
+
+    pub fn interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    // Seed of the run, recorded in every checkpoint written afterwards.
+    pub fn set_seed(&self, seed: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.seed = Some(seed);
+        if let Some(best) = &mut state.best {
+            best.seed = Some(seed);
+        }
+    }
+
+    // Continues from the best solution and the progress of the run a checkpoint was taken from.
+    pub fn resume_from(&self, checkpoint: &Checkpoint) {
+        let mut state = self.state.lock().unwrap();
+        state.completed = checkpoint.completed.clone();
+        state.draws = checkpoint.draws.clone();
+        state.best = Some(checkpoint.clone());
+    }
+
+    // Iterations run and values drawn by placement mode `stream`, recorded in every checkpoint
+    // written afterwards.
+    pub fn set_progress(&self, stream: usize, completed: usize, draws: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.completed.len() <= stream {
+            state.completed.resize(stream + 1, 0);
+            state.draws.resize(stream + 1, 0);
+        }
+        state.completed[stream] = completed;
+        state.draws[stream] = draws;
+        state.progress_dirty = true;
+    }
+
+    pub fn update(&self, score: i64, iteration: usize, solution: &Solution) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.best.as_ref().is_none_or(|best| score > best.score) {
+                state.best = Some(Checkpoint {
+                    score,
+                    iteration,
+                    seed: state.seed,
+                    completed: Vec::new(),
+                    draws: Vec::new(),
+                    solution: solution.clone(),
+                });
+                state.dirty = true;
+            }
+        }
+        self.tick()
+    }
+
+    // Saves pending changes once the interval has passed, or right away after a signal.
+    // Failing to write a checkpoint only warns, the solver keeps running.
+    pub fn tick(&self) {
+        let due = {
+            let state = self.state.lock().unwrap();
+            (state.dirty || state.progress_dirty)
+                && (self.interrupted() || state.last_save.elapsed() >= self.interval)
+        };
+        if due {
+            if let Err(err) = self.save() {
+                eprintln!("checkpoint: failed to save: {}", err);
+            }
+        }
+    }
+
+    // The output is written only when the best solution changed, the checkpoint also when the
+    // run made progress.
+    pub fn save(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let Some(best) = &mut state.best else { return Ok(()); };
+        if !state.dirty && !state.progress_dirty {
+            return Ok(());
+        }
+        best.completed.clone_from(&state.completed);
+        best.draws.clone_from(&state.draws);
+        if state.dirty {
+            best.solution.save_to_file(&self.output)?;
+            eprintln!(
+                "checkpoint: score {} at iteration {} saved to {}",
+                best.score,
+                best.iteration,
+                self.output.display()
+            );
+        }
+        best.save_to_file(&self.checkpoint_path())?;
+        state.dirty = false;
+        state.progress_dirty = false;
+        state.last_save = Instant::now();
+        Ok(())
+    }
+}
+
+// SmallRng counting the values drawn from it, so that a resumed run can continue the stream
+// where the checkpoint left it. Everything is drawn through next_u64, which makes skipping
+// `draws` values exact.
+pub struct CountingRng {
+    rng: SmallRng,
+    draws: u64,
+}
+
+impl CountingRng {
+    pub fn new(seed: u64, draws: u64) -> CountingRng {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        for _ in 0..draws {
+            rng.next_u64();
+        }
+        CountingRng { rng, draws }
+    }
+
+    pub fn draws(&self) -> u64 {
+        self.draws
+    }
+}
+
+impl RngCore for CountingRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.draws += 1;
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::geometry::*;
+
+    #[test]
+    fn test_checkpointer_keeps_best() {
+        let output = std::env::temp_dir().join(format!("checkpoint-{}.json", std::process::id()));
+        let checkpointer = Checkpointer::new(&output, Duration::from_secs(3600)).unwrap();
+        checkpointer.set_seed(42);
+        let solution = |x| Solution {
+            placements: vec![Point { x, y: 0.0 }],
+            volumes: vec![1.0],
+        };
+        checkpointer.update(10, 1, &solution(1.0));
+        checkpointer.update(30, 2, &solution(2.0));
+        checkpointer.update(20, 3, &solution(3.0));
+        checkpointer.set_progress(1, 4, 5);
+        // nothing is written before the interval has passed
+        assert!(!output.exists());
+        checkpointer.save().unwrap();
+        let checkpoint = Checkpoint::load_from_file(&checkpointer.checkpoint_path()).unwrap();
+        assert_eq!(checkpoint.score, 30);
+        assert_eq!(checkpoint.iteration, 2);
+        assert_eq!(checkpoint.seed, Some(42));
+        assert_eq!(checkpoint.completed, vec![0, 4]);
+        assert_eq!(checkpoint.draws, vec![0, 5]);
+        assert_eq!(checkpoint.solution.placements[0].x, 2.0);
+        assert!(output.exists());
+        std::fs::remove_file(&output).unwrap();
+        std::fs::remove_file(checkpointer.checkpoint_path()).unwrap();
+    }
+
+    #[test]
+    fn test_counting_rng_resume() {
+        let mut rng = CountingRng::new(7, 0);
+        let _: Vec<f64> = (0..10).map(|_| rng.gen()).collect();
+        let _ = (0..100).choose_multiple(&mut rng, 5);
+        let mut resumed = CountingRng::new(7, rng.draws());
+        let next: Vec<u32> = (0..10).map(|_| rng.gen_range(0..1000)).collect();
+        let next_resumed: Vec<u32> = (0..10).map(|_| resumed.gen_range(0..1000)).collect();
+        assert_eq!(next, next_resumed);
+    }
+}
@@ -1,90 +1,236 @@
 use crate::cache::*;
+use crate::checkpoint::*;
 use crate::common::*;
 use crate::geometry::*;
 use crate::hungarian::*;
+use crate::incremental::*;
 use crate::placement::*;
+use crate::ruin::*;
 use crate::score::*;
 use anyhow::Result;
-use rand::prelude::*;
-use rand::rngs::SmallRng;
-use rand::seq::SliceRandom;
 use rayon::prelude::*;
 use std::cmp::min;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum SolveClimbingError {
     #[error("Failed to generate any valid solutions")]
     FailedToGenerateSolution,
+    #[error("No ruin operators given")]
+    NoRuinOperators,
 }
 
-fn score_partial(prob: &Problem, placements: &[Option<Point>], volume: &[f64]) -> i64 {
-    let mut result = 0;
-    for (midx, &opt_place) in placements.iter().enumerate() {
-        let Some(place) = opt_place else { continue; };
-        'attendee: for attendee in prob.attendees.iter() {
-            if !check_pillars(attendee, place, &prob.pillars) {
-                continue;
-            }
-            for (another_midx, &another_place) in placements.iter().enumerate() {
-                if another_midx == midx {
-                    continue;
-                }
-                let Some(another_place) = another_place else {continue;};
-                if is_blocked_by_another(attendee, place, another_place) {
-                    continue 'attendee;
-                }
-            }
-            result += (volume[midx] * impact_raw(attendee, prob.musicians[midx], place) as f64)
-                .ceil() as i64;
+pub struct ClimbConfig {
+    pub iterations: usize,
+    // per placement mode
+    pub time_limit: Option<Duration>,
+    // musicians removed per iteration, min(30, M/4) when not given
+    pub destruction: Option<usize>,
+    // a random seed is drawn and printed when not given
+    pub seed: Option<u64>,
+    // iterations already run by each placement mode, when resuming
+    pub completed: Vec<usize>,
+    // values already drawn from the stream of each placement mode, when resuming
+    pub draws: Vec<u64>,
+    pub placement_modes: Vec<PlacementMode>,
+    pub log: Option<PathBuf>,
+    // solution to start from instead of an empty stage
+    pub init: Option<Solution>,
+    // chosen by adaptive weights every iteration
+    pub ruin_operators: Vec<RuinOperator>,
+    // see prune_shadowed_candidates
+    pub shadow_threshold: f64,
+}
+
+// Lines of the runs for different placement modes are interleaved, so each starts with the mode.
+struct ClimbLog(Option<Mutex<File>>);
+
+impl ClimbLog {
+    fn create(path: &Option<PathBuf>) -> Result<ClimbLog> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(File::create(path)?)),
+            None => None,
+        };
+        Ok(ClimbLog(file))
+    }
+
+    fn write(&self, mode: PlacementMode, line: &str) -> Result<()> {
+        if let Some(file) = &self.0 {
+            writeln!(file.lock().unwrap(), "{:?} {}", mode, line)?;
         }
+        Ok(())
     }
-    result
 }
 
-fn convert_to_parital_placement(
-    musician_to_place: &[Option<usize>],
-    placements: &[Point],
-) -> Vec<Option<Point>> {
-    musician_to_place
-        .iter()
-        .map(|&e| -> Option<Point> { Some(placements[e?]) })
-        .collect()
+// Musician i takes the place musician perm[i] had.
+fn permute(v: &[Option<usize>], perm: &[usize]) -> Vec<Option<usize>> {
+    perm.iter().map(|&p| v[p]).collect()
 }
 
-fn permute(v: &[Option<usize>], perm: &[usize]) -> Vec<Option<usize>> {
-    let mut res = vec![None; v.len()];
+fn permute_inv(v: &[Option<usize>], perm: &[usize]) -> Vec<Option<usize>> {
+    let mut inv = vec![0; perm.len()];
     for (i, &p) in perm.iter().enumerate() {
-        res[p] = v[i];
+        inv[p] = i;
     }
-    res
+    v.iter().map(|e| e.map(|idx| inv[idx])).collect()
 }
 
-fn permute_inv(v: &[Option<usize>], perm: &[usize]) -> Vec<Option<usize>> {
-    let mut res = vec![None; v.len()];
-    for (i, e) in v.iter().enumerate() {
-        if let Some(idx) = e {
-            res[i] = Some(perm[*idx]);
+// Building a fresh DiffCache costs about as much as this many add/remove updates.
+const MAX_INCREMENTAL_UPDATES: usize = 12;
+
+// Brings the cache to the given assignment, re-adding only musicians whose place or volume changed.
+fn sync_cache(
+    prob: &Problem,
+    cache: &mut DiffCache,
+    cache_volumes: &mut [f64],
+    places: &[Point],
+    musician_to_place: &[Option<usize>],
+    place_to_musician: &[Option<usize>],
+    volumes: &[f64],
+) {
+    let changed: Vec<_> = (0..prob.musicians.len())
+        .filter(|&midx| {
+            cache.musician_to_place[midx] != musician_to_place[midx]
+                || (musician_to_place[midx].is_some() && cache_volumes[midx] != volumes[midx])
+        })
+        .collect();
+    if changed.len() > MAX_INCREMENTAL_UPDATES {
+        *cache = DiffCache::new(prob, places, musician_to_place, place_to_musician, volumes);
+        cache_volumes.copy_from_slice(volumes);
+        return;
+    }
+    // musicians keeping their volume move from place to place once their new place is free,
+    // the others are removed and added again
+    let (mut moves, readd): (Vec<usize>, Vec<usize>) = changed.iter().partition(|&&midx| {
+        cache.musician_to_place[midx].is_some()
+            && musician_to_place[midx].is_some()
+            && cache_volumes[midx] == volumes[midx]
+    });
+    for &midx in readd.iter() {
+        if let Some(pidx) = cache.musician_to_place[midx] {
+            cache.remove_matching(prob, pidx, cache_volumes);
+        }
+    }
+    cache_volumes.copy_from_slice(volumes);
+    loop {
+        let (ready, waiting): (Vec<usize>, Vec<usize>) = moves.iter().partition(|&&midx| {
+            cache.place_to_musician[musician_to_place[midx].unwrap()].is_none()
+        });
+        if ready.is_empty() {
+            break;
+        }
+        for midx in ready {
+            let from = cache.musician_to_place[midx].unwrap();
+            let to = musician_to_place[midx].unwrap();
+            cache.move_matching(prob, midx, from, to, cache_volumes);
+        }
+        moves = waiting;
+    }
+    // what is left moves in cycles, each waiting for the place of another
+    for &midx in moves.iter() {
+        let pidx = cache.musician_to_place[midx].unwrap();
+        cache.remove_matching(prob, pidx, cache_volumes);
+    }
+    for &midx in readd.iter().chain(moves.iter()) {
+        if let Some(pidx) = musician_to_place[midx] {
+            cache.add_matching(prob, pidx, midx, cache_volumes);
         }
     }
-    res
 }
 
-fn solve_climbing_impl(prob: &Problem, placement_mode: PlacementMode) -> Result<Solution> {
-    let placement_candidates = generate_candidates(prob, placement_mode)?;
+// Moving one musician costs about as much as rebuilding this fraction of an IncrementalScore.
+const MOVES_PER_REBUILD: usize = 4;
+
+// Brings the score state to `sol`, moving only musicians whose place or volume changed.
+fn sync_score(prob: &Problem, state: &mut IncrementalScore, sol: &Solution) {
+    let moved: Vec<_> = (0..prob.musicians.len())
+        .filter(|&midx| (state.placements()[midx] - sol.placements[midx]).norm() != 0.0)
+        .collect();
+    if moved.len() * MOVES_PER_REBUILD > prob.musicians.len() {
+        *state = IncrementalScore::new(prob, sol);
+        return;
+    }
+    for &midx in moved.iter() {
+        state.move_musician(prob, midx, sol.placements[midx]);
+    }
+    for (midx, &volume) in sol.volumes.iter().enumerate() {
+        if state.volumes()[midx] != volume {
+            state.set_volume(midx, volume);
+        }
+    }
+}
+
+fn solve_climbing_impl(
+    prob: &Problem,
+    mode_idx: usize,
+    placement_mode: PlacementMode,
+    config: &ClimbConfig,
+    seed: u64,
+    log: &ClimbLog,
+    checkpointer: &Checkpointer,
+) -> Result<(Solution, usize)> {
+    let mut placement_candidates = prune_shadowed_candidates(
+        prob,
+        generate_candidates(prob, placement_mode)?,
+        config.shadow_threshold,
+    );
     let mut musician_to_place = vec![None; prob.musicians.len()];
-    let mut place_to_musician = vec![None; placement_candidates.len()];
     let mut volumes = vec![10.0; prob.musicians.len()];
-    let mut current_basic_score = 0;
-    let mut best_basic_score = i64::MIN;
+    if let Some(init) = &config.init {
+        // the initial placements become the first candidates, musician k at candidate k
+        placement_candidates = merge_candidates(&init.placements, &placement_candidates);
+        musician_to_place = (0..prob.musicians.len()).map(Some).collect();
+        volumes.copy_from_slice(&init.volumes);
+    }
+    let mut place_to_musician = vec![None; placement_candidates.len()];
+    for (midx, &pidx) in musician_to_place.iter().enumerate() {
+        if let Some(pidx) = pidx {
+            place_to_musician[pidx] = Some(midx);
+        }
+    }
+    let mut state: Option<IncrementalScore> = None;
+    let mut best_score = i64::MIN;
     let mut best_sol = None;
     let mut best_p2m = None;
     let mut best_m2p = None;
-    let mut rng = SmallRng::from_entropy();
+    let mut best_contributions = Vec::new();
+    let mut weights = RuinWeights::new(&config.ruin_operators);
+    let mut last_operator = None;
+    let mut rng = CountingRng::new(seed, config.draws.get(mode_idx).copied().unwrap_or(0));
+    let destruction = config
+        .destruction
+        .unwrap_or(min(30, prob.musicians.len() / 4))
+        .min(prob.musicians.len());
+    let start = Instant::now();
+    let mut cache = DiffCache::new(
+        prob,
+        &placement_candidates,
+        &musician_to_place,
+        &place_to_musician,
+        &volumes,
+    );
+    let mut cache_volumes = volumes.clone();
+    let completed = config.completed.get(mode_idx).copied().unwrap_or(0);
+    let mut iterations = completed;
 
-    for _i in 0..100 {
-        let mut cache = DiffCache::new(
+    for iteration in completed..config.iterations {
+        if checkpointer.interrupted()
+            || config
+                .time_limit
+                .is_some_and(|limit| start.elapsed() >= limit)
+        {
+            break;
+        }
+        iterations = iteration + 1;
+        sync_cache(
             prob,
+            &mut cache,
+            &mut cache_volumes,
             &placement_candidates,
             &musician_to_place,
             &place_to_musician,
@@ -94,14 +240,14 @@ fn solve_climbing_impl(prob: &Problem, placement_mode: PlacementMode) -> Result<
 
         // place musicians greedy
         while remain > 0 {
-            let (i, j, d, v) = cache.find_best_matching();
-            current_basic_score += d;
+            let (i, j, _d, v) = cache.find_best_matching();
             musician_to_place[j] = Some(i);
             place_to_musician[i] = Some(j);
             volumes[j] = v;
             cache.add_matching(prob, i, j, &volumes);
             remain -= 1;
         }
+        cache_volumes.copy_from_slice(&volumes);
 
         let placements = musician_to_place
             .iter()
@@ -116,62 +262,132 @@ fn solve_climbing_impl(prob: &Problem, placement_mode: PlacementMode) -> Result<
         musician_to_place = permute(&musician_to_place, &permutation);
         place_to_musician = permute_inv(&place_to_musician, &permutation);
         volumes.copy_from_slice(&optimized.volumes);
-        current_basic_score = score_partial(
-            prob,
-            &convert_to_parital_placement(&musician_to_place, &placement_candidates),
-            &volumes,
-        );
+        let state = state.get_or_insert_with(|| IncrementalScore::new(prob, &optimized));
+        sync_score(prob, state, &optimized);
+        let current_score = state.score();
 
-        if current_basic_score > best_basic_score {
+        let improved = current_score > best_score;
+        if let Some((idx, _)) = last_operator {
+            weights.reward(idx, improved);
+        }
+        if improved {
+            checkpointer.update(current_score, iterations, &optimized);
             best_sol = Some(optimized);
-            best_basic_score = current_basic_score;
+            best_contributions = state.contributions().to_vec();
+            best_score = current_score;
             best_p2m = Some(place_to_musician.clone());
             best_m2p = Some(musician_to_place.clone());
         } else {
             place_to_musician.copy_from_slice(&best_p2m.clone().unwrap());
             musician_to_place.copy_from_slice(&best_m2p.clone().unwrap());
             volumes.copy_from_slice(&best_sol.clone().unwrap().volumes);
+            checkpointer.tick();
         }
-        eprintln!("{} {}", best_basic_score, current_basic_score);
+        log.write(
+            placement_mode,
+            &format!(
+                "iteration={} best={} current={} operator={} elapsed={:.1}",
+                iteration,
+                best_score,
+                current_score,
+                last_operator.map_or("-".to_string(), |(_, op)| format!("{:?}", op)),
+                start.elapsed().as_secs_f64()
+            ),
+        )?;
 
-        let k = min(30, prob.musicians.len() / 4);
-        let destruction_target: Vec<_> = musician_to_place
-            .choose_multiple(&mut rng, k)
-            .cloned()
+        let (idx, operator) = weights.choose(&mut rng);
+        last_operator = Some((idx, operator));
+        let placements: Vec<_> = musician_to_place
+            .iter()
+            .map(|e| placement_candidates[e.unwrap()])
             .collect();
-        for opt_pidx in destruction_target {
-            let pidx = opt_pidx.unwrap();
-            let midx = place_to_musician[pidx].unwrap();
+        let destruction_target = ruin(
+            operator,
+            prob,
+            &placements,
+            &best_contributions,
+            destruction,
+            &mut rng,
+        );
+        for midx in destruction_target {
+            let pidx = musician_to_place[midx].unwrap();
             place_to_musician[pidx] = None;
             musician_to_place[midx] = None;
             volumes[midx] = 10.0;
         }
-        let partial_placement =
-            convert_to_parital_placement(&musician_to_place, &placement_candidates);
-        current_basic_score = score_partial(prob, &partial_placement, &volumes);
+        checkpointer.set_progress(mode_idx, iterations, rng.draws());
     }
-    best_sol.ok_or(SolveClimbingError::FailedToGenerateSolution.into())
+    log.write(placement_mode, &weights.summary())?;
+    let sol = best_sol.ok_or(SolveClimbingError::FailedToGenerateSolution)?;
+    Ok((sol, iterations))
 }
 
-pub fn solve_climbing(prob: &Problem) -> Result<Solution> {
-    let placement_modes = [
-        PlacementMode::GridNormal(InterpolateMode::Strech),
-        PlacementMode::GridNormal(InterpolateMode::Corner(10.0)),
-        //PlacementMode::GridDiag,
-        //PlacementMode::GridCompress,
-    ];
-    let mut param_packs = Vec::new();
-    for pmode in placement_modes {
-        param_packs.push(pmode);
-    }
-    let sol = param_packs
+// Each placement mode runs with its own seed derived from the configured one, so the same
+// seed gives the same solution as long as the time limit is not what stops the runs.
+// The best solution so far is reported to `checkpointer`, and all runs stop early on a signal.
+pub fn solve_climbing(
+    prob: &Problem,
+    config: &ClimbConfig,
+    checkpointer: &Checkpointer,
+) -> Result<Solution> {
+    if config.ruin_operators.is_empty() {
+        return Err(SolveClimbingError::NoRuinOperators.into());
+    }
+    if let Some(init) = &config.init {
+        ensure_valid(prob, init)?;
+    }
+    let seed = config.seed.unwrap_or_else(rand::random);
+    eprintln!("seed: {}", seed);
+    checkpointer.set_seed(seed);
+    let log = ClimbLog::create(&config.log)?;
+    // never return anything worse than the initial solution
+    let init = match &config.init {
+        Some(init) => Some((score(prob, init, true)?, init.clone())),
+        None => None,
+    };
+    if let Some((init_score, init)) = &init {
+        checkpointer.update(*init_score, 0, init);
+    }
+    let (_, sol) = config
+        .placement_modes
         .par_iter()
-        .filter_map(|&pmode| solve_climbing_impl(prob, pmode).ok())
-        .flat_map(|sol| {
-            let hg = optimize_hungarian(prob, &sol).unwrap();
-            [sol, hg]
+        .enumerate()
+        .filter_map(|(i, &pmode)| {
+            let mode_seed = seed.wrapping_add(i as u64);
+            solve_climbing_impl(prob, i, pmode, config, mode_seed, &log, checkpointer).ok()
+        })
+        .filter_map(|(sol, iterations)| {
+            let result = optimize_hungarian(prob, &sol, false).ok()?;
+            checkpointer.update(result.after, iterations, &result.solution);
+            Some(result)
         })
-        .max_by_key(|sol| score(prob, sol, true).unwrap())
+        .map(|result| (result.after, result.solution))
+        .chain(init)
+        .max_by_key(|(score, _)| *score)
         .ok_or(SolveClimbingError::FailedToGenerateSolution)?;
     Ok(sol)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_permute_inv() {
+        let perm = vec![2, 0, 3, 1];
+        let identity: Vec<_> = (0..4).map(Some).collect();
+        assert_eq!(permute_inv(&permute(&identity, &perm), &perm), identity);
+
+        // the two maps stay inverse to each other after a reassignment
+        let musician_to_place = vec![Some(4), Some(1), Some(5), Some(0)];
+        let mut place_to_musician = vec![None; 6];
+        for (midx, &pidx) in musician_to_place.iter().enumerate() {
+            place_to_musician[pidx.unwrap()] = Some(midx);
+        }
+        let musician_to_place = permute(&musician_to_place, &perm);
+        let place_to_musician = permute_inv(&place_to_musician, &perm);
+        for (midx, &pidx) in musician_to_place.iter().enumerate() {
+            assert_eq!(place_to_musician[pidx.unwrap()], Some(midx));
+        }
+    }
+}
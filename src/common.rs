@@ -4,10 +4,11 @@ use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use thiserror::Error;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Attendee {
     pub x: f64,
     pub y: f64,
@@ -23,7 +24,7 @@ impl Attendee {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Pillar {
     pub center: (f64, f64),
     pub radius: f64,
@@ -38,7 +39,7 @@ impl Pillar {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Problem {
     pub room_width: f64,
     pub room_height: f64,
@@ -77,11 +78,10 @@ impl Problem {
 }
 
 #[tokio::main]
-pub async fn download_problems(id_from: u32, id_to: u32, output: &PathBuf) {
+pub async fn download_problems(id_from: u32, id_to: u32, output: &Path) {
     let client = Arc::new(Client::new());
     let fut = futures::future::join_all(
         (id_from..=id_to)
-            .into_iter()
             .map(|id| {
                 let client = client.clone();
                 let path = output.join(format!("{}.json", id));
@@ -106,6 +106,59 @@ pub struct Solution {
     pub volumes: Vec<f64>,
 }
 
+// On-disk form of Solution; files written before volumes were introduced lack the field.
+#[derive(Deserialize)]
+struct SolutionFile {
+    placements: Vec<Point>,
+    volumes: Option<Vec<f64>>,
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    #[error("musician {musician} is not inside the stage or too close to the edge of the stage")]
+    OutsideStage { musician: usize },
+    #[error("musician {musician} is not far enough from {another} (distance {distance})")]
+    TooClose {
+        musician: usize,
+        another: usize,
+        distance: f64,
+    },
+    #[error("expected {expected} placements, but got {actual}")]
+    PlacementCount { expected: usize, actual: usize },
+    #[error("expected {expected} volumes, but got {actual}")]
+    VolumeCount { expected: usize, actual: usize },
+    #[error("volume {volume} of musician {musician} is out of [0, 10]")]
+    VolumeOutOfRange { musician: usize, volume: f64 },
+    #[error("musician {musician} has NaN coordinates")]
+    NanCoordinate { musician: usize },
+}
+
+pub fn format_violations(errors: &[ValidationError]) -> String {
+    errors.iter().map(|e| format!("\n  {}", e)).collect()
+}
+
+pub fn validate_volumes(sol: &Solution) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    if sol.volumes.len() != sol.placements.len() {
+        errors.push(ValidationError::VolumeCount {
+            expected: sol.placements.len(),
+            actual: sol.volumes.len(),
+        });
+    }
+    for (musician, &volume) in sol.volumes.iter().enumerate() {
+        if !(0.0..=10.0).contains(&volume) {
+            errors.push(ValidationError::VolumeOutOfRange { musician, volume });
+        }
+    }
+    errors
+}
+
+#[derive(Error, Debug)]
+pub enum LoadSolutionError {
+    #[error("Invalid volumes:{}", format_violations(.0))]
+    InvalidVolumes(Vec<ValidationError>),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Submission {
     pub problem_id: u32,
@@ -116,7 +169,19 @@ impl Solution {
     pub fn load_from_file(path: &PathBuf) -> Result<Self> {
         let f = File::open(path)?;
         let reader = BufReader::new(f);
-        Ok(serde_json::from_reader(reader)?)
+        let file: SolutionFile = serde_json::from_reader(reader)?;
+        let volumes = file
+            .volumes
+            .unwrap_or_else(|| vec![1.0; file.placements.len()]);
+        let sol = Solution {
+            placements: file.placements,
+            volumes,
+        };
+        let errors = validate_volumes(&sol);
+        if !errors.is_empty() {
+            return Err(LoadSolutionError::InvalidVolumes(errors).into());
+        }
+        Ok(sol)
     }
 
     pub fn save_to_file(&self, path: &PathBuf) -> Result<()> {
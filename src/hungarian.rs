@@ -1,21 +1,36 @@
-use crate::common::Pillar;
 use crate::common::*;
 use crate::geometry::*;
+use crate::incremental::*;
+use crate::placement::*;
 use crate::score::*;
+use crate::spatial::*;
 use anyhow::Result;
-use std::cmp::max;
+use rayon::prelude::*;
+
+// Potentials beyond this are reported, as the reduced costs computed from them may overflow soon.
+const POTENTIAL_WARNING_LIMIT: i128 = i128::MAX / 1024;
+
+pub struct HungarianResult {
+    pub solution: Solution,
+    pub before: i64,
+    pub after: i64,
+}
 
 // https://ei1333.github.io/library/graph/flow/hungarian.hpp
+// Every row is matched to a distinct column, so there must be at least as many columns as rows.
 fn hungarian_impl(mat: &[Vec<i64>]) -> Vec<usize> {
-    let m = mat.len();
+    let n = mat.len();
+    let m = mat.first().map_or(0, |row| row.len());
+    assert!(n <= m);
     let mut p = vec![0; m];
     let mut way = vec![0; m];
-    let mut u = vec![0_i128; m];
+    let mut u = vec![0_i128; n];
     let mut v = vec![0_i128; m];
     let mut min_v;
     let mut used;
+    let mut warned = false;
 
-    for i in 1..m {
+    for i in 1..n {
         p[0] = i;
         min_v = vec![i128::MAX; m];
         used = vec![false; m];
@@ -54,40 +69,186 @@ fn hungarian_impl(mat: &[Vec<i64>]) -> Vec<usize> {
             j0 = way[j0];
             j0 != 0
         } {}
+        let potential = u.iter().chain(v.iter()).map(|x| x.abs()).max().unwrap_or(0);
+        if !warned && potential > POTENTIAL_WARNING_LIMIT {
+            eprintln!(
+                "warning: hungarian potential {} is close to the i128 limit",
+                potential
+            );
+            warned = true;
+        }
     }
     p[0] = 0;
 
     p
 }
 
-pub fn optimize_hungarian(prob: &Problem, sol: &Solution) -> Result<Solution> {
+// Reassigns musicians among the current placements. With `repeat`, the assignment is redone
+// with the new blocking until the score stops improving. The input is returned when nothing
+// improves it.
+pub fn optimize_hungarian(prob: &Problem, sol: &Solution, repeat: bool) -> Result<HungarianResult> {
+    let before = score(prob, sol, true)?;
+    let mut best = sol.clone();
+    let mut after = before;
+    loop {
+        let next = assign_hungarian(prob, &best);
+        let next_score = score(prob, &next, true)?;
+        if next_score <= after {
+            break;
+        }
+        best = next;
+        after = next_score;
+        if !repeat {
+            break;
+        }
+    }
+    Ok(HungarianResult {
+        solution: best,
+        before,
+        after,
+    })
+}
+
+fn assign_hungarian(prob: &Problem, sol: &Solution) -> Solution {
     let m: usize = prob.musicians.len();
-    let score_contrib_table = create_score_contrib_table(prob, &sol.placements, &prob.pillars);
+    let score_contrib_table = create_score_contrib_table(prob, &sol.placements);
     let mut placements = vec![Point { x: 0.0, y: 0.0 }; m];
     let mat = convert_matrix_for_hungarian(&score_contrib_table);
     let p = hungarian_impl(&mat);
 
-    for i in 1..=m {
-        let new_musician_idx = p[i] - 1;
+    for (i, &pi) in p.iter().enumerate().skip(1) {
+        let new_musician_idx = pi - 1;
         let placement_idx = i - 1;
         placements[new_musician_idx] = sol.placements[placement_idx];
     }
-    Ok(Solution {
+    Solution {
         placements,
         volumes: sol.volumes.clone(),
+    }
+}
+
+// Passes over all pairs in the swap search of optimize_hungarian_together.
+const MAX_SWAP_PASSES: usize = 10;
+
+// Estimates the score change of swapping two musicians of different kinds. The occupied places do
+// not change under a swap, so neither does the raw contribution of a kind at each of them; only
+// the play-together scalars of the two kinds involved move. Rounding of each term is ignored.
+struct SwapEstimator<'a> {
+    kinds: &'a [u32],
+    places: Vec<Point>,
+    volumes: Vec<f64>,
+    // raw contribution of each kind at each of `places`
+    table: Vec<Vec<i64>>,
+    members: Vec<Vec<usize>>,
+    // index in `places` of the place each musician stands at
+    spot: Vec<usize>,
+}
+
+impl SwapEstimator<'_> {
+    fn new<'a>(prob: &'a Problem, sol: &Solution) -> SwapEstimator<'a> {
+        let table = create_kind_contrib_table(prob, &sol.placements, &sol.placements);
+        let num_kinds = prob.attendees.first().map_or(0, |a| a.tastes.len());
+        let mut members = vec![Vec::new(); num_kinds];
+        for (midx, &kind) in prob.musicians.iter().enumerate() {
+            members[kind as usize].push(midx);
+        }
+        SwapEstimator {
+            kinds: &prob.musicians,
+            places: sol.placements.clone(),
+            volumes: sol.volumes.clone(),
+            table,
+            members,
+            spot: (0..prob.musicians.len()).collect(),
+        }
+    }
+
+    fn estimate(&self, a: usize, b: usize) -> f64 {
+        let mut delta = 0.0;
+        for (moved, from, to) in [
+            (a, self.spot[a], self.spot[b]),
+            (b, self.spot[b], self.spot[a]),
+        ] {
+            let kind = self.kinds[moved] as usize;
+            let (mut scalar_from, mut scalar_to) = (1.0, 1.0);
+            for &other in &self.members[kind] {
+                if other == moved {
+                    continue;
+                }
+                let place = self.places[self.spot[other]];
+                let inv_from = 1.0 / (place - self.places[from]).length();
+                let inv_to = 1.0 / (place - self.places[to]).length();
+                scalar_from += inv_from;
+                scalar_to += inv_to;
+                delta += self.volumes[other]
+                    * self.table[self.spot[other]][kind] as f64
+                    * (inv_to - inv_from);
+            }
+            delta += self.volumes[moved]
+                * (self.table[to][kind] as f64 * scalar_to
+                    - self.table[from][kind] as f64 * scalar_from);
+        }
+        delta
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.spot.swap(a, b);
+    }
+}
+
+// For full-division problems, where optimize_hungarian ignores the play-together bonus.
+// Starts from the Hungarian result and swaps musicians of different kinds. Only swaps that
+// SwapEstimator expects to gain are tried on the exact score, and kept when it improves, so the
+// result is never worse than `sol`. Stops after a pass without improvement or MAX_SWAP_PASSES.
+pub fn optimize_hungarian_together(
+    prob: &Problem,
+    sol: &Solution,
+    repeat: bool,
+) -> Result<HungarianResult> {
+    let seed = optimize_hungarian(prob, sol, repeat)?;
+    if !is_full_division_scoring(prob) {
+        return Ok(seed);
+    }
+    let m = prob.musicians.len();
+    let mut state = IncrementalScore::new(prob, &seed.solution);
+    let mut estimator = SwapEstimator::new(prob, &seed.solution);
+    for _ in 0..MAX_SWAP_PASSES {
+        let mut swapped = 0;
+        for a in 0..m {
+            for b in a + 1..m {
+                if prob.musicians[a] == prob.musicians[b] || estimator.estimate(a, b) <= 0.0 {
+                    continue;
+                }
+                let before = state.score();
+                state.swap_musicians(prob, a, b);
+                if state.score() > before {
+                    estimator.swap(a, b);
+                    swapped += 1;
+                } else {
+                    state.swap_musicians(prob, a, b);
+                }
+            }
+        }
+        if swapped == 0 {
+            break;
+        }
+    }
+    Ok(HungarianResult {
+        solution: state.solution(),
+        before: seed.before,
+        after: state.score(),
     })
 }
 
 pub fn optimize_hungarian_2(prob: &Problem, sol: &Solution) -> (Solution, Vec<usize>) {
     let m: usize = prob.musicians.len();
-    let score_contrib_table = create_score_contrib_table(prob, &sol.placements, &prob.pillars);
+    let score_contrib_table = create_score_contrib_table(prob, &sol.placements);
     let mut placements = vec![Point { x: 0.0, y: 0.0 }; m];
     let mat = convert_matrix_for_hungarian(&score_contrib_table);
     let p = hungarian_impl(&mat);
 
     let mut perm = vec![0; m];
-    for i in 1..=m {
-        let new_musician_idx = p[i] - 1;
+    for (i, &pi) in p.iter().enumerate().skip(1) {
+        let new_musician_idx = pi - 1;
         let placement_idx = i - 1;
         placements[new_musician_idx] = sol.placements[placement_idx];
         perm[new_musician_idx] = placement_idx;
@@ -101,6 +262,160 @@ pub fn optimize_hungarian_2(prob: &Problem, sol: &Solution) -> (Solution, Vec<us
     )
 }
 
+// Raw contribution of each kind at each spot, with the musicians held at `placements`.
+// A spot that is one of the placements is not blocked by the musician standing there.
+fn create_kind_contrib_table(
+    prob: &Problem,
+    placements: &[Point],
+    spots: &[Point],
+) -> Vec<Vec<i64>> {
+    let num_kinds = prob.attendees.first().map_or(0, |a| a.tastes.len());
+    spots
+        .par_iter()
+        .enumerate()
+        .map(|(spot_idx, &place)| {
+            let others = placements
+                .iter()
+                .enumerate()
+                .filter(|&(idx, _)| idx != spot_idx)
+                .map(|(_, &p)| p);
+            let visible = visible_attendees_from(prob, place, others);
+            let mut row = vec![0; num_kinds];
+            for (attendee, _) in prob.attendees.iter().zip(visible).filter(|&(_, vis)| vis) {
+                for (kind, contrib) in row.iter_mut().enumerate() {
+                    *contrib += impact_raw(attendee, kind as u32, place);
+                }
+            }
+            row
+        })
+        .collect()
+}
+
+// Score lost by the musicians at `placements` when someone else stands at each of `spots`.
+fn create_blocking_penalty(
+    prob: &Problem,
+    placements: &[Point],
+    volumes: &[f64],
+    spots: &[Point],
+) -> Vec<i64> {
+    let grid = SpatialGrid::new(spots, 5.0);
+    placements
+        .par_iter()
+        .enumerate()
+        .map(|(musician_idx, &place)| {
+            let kind = prob.musicians[musician_idx];
+            let others = placements
+                .iter()
+                .enumerate()
+                .filter(|&(idx, _)| idx != musician_idx)
+                .map(|(_, &p)| p);
+            let visible = visible_attendees_from(prob, place, others);
+            let mut penalty = vec![0; spots.len()];
+            let mut hit = Vec::new();
+            for (attendee, _) in prob.attendees.iter().zip(visible).filter(|&(_, vis)| vis) {
+                let line = Line {
+                    p1: attendee.place(),
+                    p2: place,
+                };
+                hit.clear();
+                grid.any_near_segment(place, attendee.place(), |idx| {
+                    if is_cross_line_circle(
+                        line,
+                        Circle {
+                            c: spots[idx],
+                            r: 5.0,
+                        },
+                    ) {
+                        hit.push(idx);
+                    }
+                    false
+                });
+                hit.sort_unstable();
+                hit.dedup();
+                let loss = (impact_raw(attendee, kind, place) as f64 * volumes[musician_idx]).ceil()
+                    as i64;
+                for &idx in &hit {
+                    penalty[idx] -= loss;
+                }
+            }
+            penalty
+        })
+        .reduce(
+            || vec![0; spots.len()],
+            |mut a, b| {
+                a.iter_mut().zip(b).for_each(|(x, y)| *x += y);
+                a
+            },
+        )
+}
+
+// Like optimize_hungarian, but musicians may also move to empty spots among the candidates of
+// `mode`. Contributions are weighted by volume, and an empty spot is charged for the lines of
+// the current placements it would block. Only the `max_candidates` best empty spots are
+// considered. Blocking is recomputed and the assignment repeated while the score improves.
+pub fn optimize_hungarian_candidates(
+    prob: &Problem,
+    sol: &Solution,
+    mode: PlacementMode,
+    max_candidates: usize,
+) -> Result<HungarianResult> {
+    let m = prob.musicians.len();
+    let candidates = generate_candidates(prob, mode)?;
+    let mut best = sol.clone();
+    let before = score(prob, &best, true)?;
+    let mut best_score = before;
+    loop {
+        let spots = merge_candidates(&best.placements, &candidates);
+        let table = create_kind_contrib_table(prob, &best.placements, &spots);
+        let penalty = create_blocking_penalty(prob, &best.placements, &best.volumes, &spots[m..]);
+        let value = |musician_idx: usize, spot_idx: usize| {
+            let kind = prob.musicians[musician_idx] as usize;
+            let own = (table[spot_idx][kind] as f64 * best.volumes[musician_idx]).ceil() as i64;
+            if spot_idx < m {
+                own
+            } else {
+                own + penalty[spot_idx - m]
+            }
+        };
+        let mut free: Vec<_> = (m..spots.len()).collect();
+        free.sort_by_cached_key(|&spot_idx| {
+            std::cmp::Reverse((0..m).map(|k| value(k, spot_idx)).max().unwrap_or(0))
+        });
+        free.truncate(max_candidates);
+        let chosen: Vec<_> = (0..m).chain(free).collect();
+
+        let mut contrib_table = vec![vec![0; chosen.len() + 1]; m + 1];
+        for (musician_idx, row) in contrib_table.iter_mut().skip(1).enumerate() {
+            for (i, &spot_idx) in chosen.iter().enumerate() {
+                row[i + 1] = value(musician_idx, spot_idx);
+            }
+        }
+        let p = hungarian_impl(&convert_matrix_for_hungarian(&contrib_table));
+
+        let mut placements = best.placements.clone();
+        for (i, &pi) in p.iter().enumerate().skip(1) {
+            if pi != 0 {
+                placements[pi - 1] = spots[chosen[i - 1]];
+            }
+        }
+        let next = Solution {
+            placements,
+            volumes: best.volumes.clone(),
+        };
+        let next_score = score(prob, &next, true)?;
+        if next_score <= best_score {
+            break;
+        }
+        best = next;
+        best_score = next_score;
+    }
+    Ok(HungarianResult {
+        solution: best,
+        before,
+        after: best_score,
+    })
+}
+
 fn convert_matrix_for_hungarian(orig: &[Vec<i64>]) -> Vec<Vec<i64>> {
     let mut mat = orig.to_vec();
     //for row in mat.iter_mut() {
@@ -109,28 +424,30 @@ fn convert_matrix_for_hungarian(orig: &[Vec<i64>]) -> Vec<Vec<i64>> {
     //    }
     //}
     let max = *mat.iter().flatten().max().unwrap_or(&0);
-    let m = mat.len();
-    for i in 1..m {
-        for j in 1..m {
-            mat[i][j] = max - mat[i][j];
+    for row in mat.iter_mut().skip(1) {
+        for elem in row.iter_mut().skip(1) {
+            *elem = max - *elem;
         }
     }
     mat
 }
 
-fn create_score_contrib_table(
-    prob: &Problem,
-    placements: &[Point],
-    pillars: &[Pillar],
-) -> Vec<Vec<i64>> {
+fn create_score_contrib_table(prob: &Problem, placements: &[Point]) -> Vec<Vec<i64>> {
     let m: usize = placements.len();
 
     let mut score_contrib_table = vec![vec![0; m + 1]; m + 1];
-    for (placement_idx, _) in placements.iter().enumerate() {
+    for (placement_idx, &place) in placements.iter().enumerate() {
+        let others = placements
+            .iter()
+            .enumerate()
+            .filter(|&(idx, _)| idx != placement_idx)
+            .map(|(_, &p)| p);
         let impact_attendees = prob
             .attendees
             .iter()
-            .filter(|&attendee| check_impact_attendee(attendee, pillars, placements, placement_idx))
+            .zip(visible_attendees_from(prob, place, others))
+            .filter(|&(_, vis)| vis)
+            .map(|(attendee, _)| attendee)
             .collect::<Vec<&Attendee>>();
         for (musician_idx, kind) in prob.musicians.iter().enumerate() {
             score_contrib_table[musician_idx + 1][placement_idx + 1] =
@@ -140,22 +457,6 @@ fn create_score_contrib_table(
     score_contrib_table
 }
 
-fn check_impact_attendee(
-    attendee: &Attendee,
-    pillars: &[Pillar],
-    placements: &[Point],
-    placement_idx: usize,
-) -> bool {
-    if !check_other_musicians(attendee, placements, placement_idx) {
-        return false;
-    }
-    let place = placements[placement_idx];
-    if !check_pillars(attendee, place, pillars) {
-        return false;
-    }
-    true
-}
-
 fn calc_score_contrib(
     attendees: &[&Attendee],
     kind: &u32,
@@ -172,6 +473,9 @@ fn calc_score_contrib(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::incremental::test::{random_placements, random_problem};
+    use rand::prelude::*;
+    use rand::rngs::SmallRng;
 
     #[test]
     fn test_hungarian() {
@@ -184,5 +488,44 @@ mod test {
         ];
         let p = hungarian_impl(&mat);
         assert_eq!(p, vec![0, 4, 1, 3, 2]);
+
+        let mat = vec![vec![0, 0, 0, 0], vec![0, 5, 1, 7], vec![0, 6, 2, 3]];
+        let p = hungarian_impl(&mat);
+        assert_eq!(p, vec![0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_swap_estimate() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let prob = random_problem(&mut rng, true);
+        let placements = random_placements(&mut rng, &prob);
+        let sol = Solution {
+            placements,
+            volumes: (0..prob.musicians.len())
+                .map(|_| rng.gen_range(0.0..10.0))
+                .collect(),
+        };
+        let mut state = IncrementalScore::new(&prob, &sol);
+        let mut estimator = SwapEstimator::new(&prob, &sol);
+        let m = prob.musicians.len();
+        for _ in 0..100 {
+            let (a, b) = (rng.gen_range(0..m), rng.gen_range(0..m));
+            if prob.musicians[a] == prob.musicians[b] {
+                continue;
+            }
+            let before = state.score();
+            state.swap_musicians(&prob, a, b);
+            let actual = (state.score() - before) as f64;
+            // each term of the musicians of both kinds is rounded up, before and after the swap
+            let noise = (2 * m / 3 * prob.attendees.len() * 2) as f64;
+            let estimate = estimator.estimate(a, b);
+            assert!(
+                (actual - estimate).abs() <= noise,
+                "{} {}",
+                actual,
+                estimate
+            );
+            estimator.swap(a, b);
+        }
     }
 }
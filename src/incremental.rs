@@ -0,0 +1,429 @@
+use crate::common::*;
+use crate::geometry::*;
+use crate::score::*;
+use crate::visibility::*;
+use std::f64::consts::PI;
+
+const ANGLE_EPS: f64 = 1e-7;
+
+// Keeps, for every (musician, attendee) pair, how many other musicians block the line,
+// so that the exact score can be updated after local changes without a full recomputation.
+// Every term is computed the same way as in score::score, so the total always matches it.
+pub struct IncrementalScore {
+    placements: Vec<Point>,
+    volumes: Vec<f64>,
+    scalar: Vec<f64>,
+    pillar_visible: Vec<Vec<bool>>,
+    blocked: Vec<Vec<u32>>,
+    impacts: Vec<Vec<i64>>,
+    // attendees sorted by the direction seen from each musician
+    angles: Vec<Vec<(f64, usize)>>,
+    contribution: Vec<i64>,
+    total: i64,
+}
+
+fn sorted_angles(prob: &Problem, place: Point) -> Vec<(f64, usize)> {
+    let mut angles: Vec<_> = prob
+        .attendees
+        .iter()
+        .enumerate()
+        .map(|(j, attendee)| {
+            let v = attendee.place() - place;
+            (v.y.atan2(v.x), j)
+        })
+        .collect();
+    angles.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+    angles
+}
+
+// Attendees whose line to `place` may cross the 5-unit circle around `center`.
+fn shadowed_attendees(
+    angles: &[(f64, usize)],
+    place: Point,
+    center: Point,
+) -> impl Iterator<Item = usize> + '_ {
+    let empty = (f64::INFINITY, f64::NEG_INFINITY);
+    let v = center - place;
+    let dist = v.length();
+    let ranges = if dist <= 5.0 {
+        [(f64::NEG_INFINITY, f64::INFINITY), empty]
+    } else {
+        let mid = v.y.atan2(v.x);
+        let half = (5.0 / dist).asin() + ANGLE_EPS;
+        let (lo, hi) = (mid - half, mid + half);
+        if lo < -PI {
+            [(lo + 2. * PI, PI), (-PI, hi)]
+        } else if hi > PI {
+            [(lo, PI), (-PI, hi - 2. * PI)]
+        } else {
+            [(lo, hi), empty]
+        }
+    };
+    ranges.into_iter().flat_map(move |(lo, hi)| {
+        let begin = angles.partition_point(|&(a, _)| a < lo);
+        let end = angles.partition_point(|&(a, _)| a <= hi);
+        angles[begin..end.max(begin)].iter().map(|&(_, j)| j)
+    })
+}
+
+fn is_blocked_at(attendee: &Attendee, place: Point, center: Point) -> bool {
+    let line = Line {
+        p1: attendee.place(),
+        p2: place,
+    };
+    is_cross_line_circle(line, Circle { c: center, r: 5.0 })
+}
+
+impl IncrementalScore {
+    pub fn new(prob: &Problem, sol: &Solution) -> IncrementalScore {
+        let m = sol.placements.len();
+        let mut state = IncrementalScore {
+            placements: sol.placements.clone(),
+            volumes: sol.volumes.clone(),
+            scalar: vec![1.0; m],
+            pillar_visible: vec![Vec::new(); m],
+            blocked: vec![Vec::new(); m],
+            impacts: vec![Vec::new(); m],
+            angles: vec![Vec::new(); m],
+            contribution: vec![0; m],
+            total: 0,
+        };
+        for midx in 0..m {
+            state.reset_musician(prob, midx);
+        }
+        for midx in 0..m {
+            state.scalar[midx] = play_together_scalar_of(prob, &state.placements, midx);
+            state.recompute_contribution(midx);
+        }
+        state
+    }
+
+    pub fn score(&self) -> i64 {
+        self.total
+    }
+
+    pub fn placements(&self) -> &[Point] {
+        &self.placements
+    }
+
+    pub fn volumes(&self) -> &[f64] {
+        &self.volumes
+    }
+
+    pub fn contributions(&self) -> &[i64] {
+        &self.contribution
+    }
+
+    pub fn solution(&self) -> Solution {
+        Solution {
+            placements: self.placements.clone(),
+            volumes: self.volumes.clone(),
+        }
+    }
+
+    fn term(&self, midx: usize, aidx: usize) -> i64 {
+        if !self.pillar_visible[midx][aidx] || self.blocked[midx][aidx] > 0 {
+            return 0;
+        }
+        (self.volumes[midx] * self.impacts[midx][aidx] as f64 * self.scalar[midx]).ceil() as i64
+    }
+
+    fn recompute_contribution(&mut self, midx: usize) {
+        let contribution = (0..self.impacts[midx].len())
+            .map(|aidx| self.term(midx, aidx))
+            .sum();
+        self.total += contribution - self.contribution[midx];
+        self.contribution[midx] = contribution;
+    }
+
+    fn recompute_scalar_of_kind(&mut self, prob: &Problem, kind: u32) {
+        if !is_full_division_scoring(prob) {
+            return;
+        }
+        for midx in 0..self.placements.len() {
+            if prob.musicians[midx] != kind {
+                continue;
+            }
+            self.scalar[midx] = play_together_scalar_of(prob, &self.placements, midx);
+            self.recompute_contribution(midx);
+        }
+    }
+
+    // Rebuilds everything about the lines of `midx` itself; contribution is left stale.
+    fn reset_musician(&mut self, prob: &Problem, midx: usize) {
+        let place = self.placements[midx];
+        let kind = prob.musicians[midx];
+        let pillars: Vec<_> = prob
+            .pillars
+            .iter()
+            .map(|pillar| Circle {
+                c: pillar.c(),
+                r: pillar.radius,
+            })
+            .collect();
+        self.pillar_visible[midx] = visible_attendees(place, &prob.attendees, &pillars);
+        self.impacts[midx] = prob
+            .attendees
+            .iter()
+            .map(|attendee| impact_raw(attendee, kind, place))
+            .collect();
+        self.angles[midx] = sorted_angles(prob, place);
+        let mut blocked = vec![0; prob.attendees.len()];
+        for (another, &center) in self.placements.iter().enumerate() {
+            if another == midx {
+                continue;
+            }
+            for aidx in shadowed_attendees(&self.angles[midx], place, center) {
+                if is_blocked_at(&prob.attendees[aidx], place, center) {
+                    blocked[aidx] += 1;
+                }
+            }
+        }
+        self.blocked[midx] = blocked;
+    }
+
+    // Adds (`delta` = 1) or removes (`delta` = -1) a blocker at `center` from the lines of
+    // every musician except `skip`.
+    fn update_blocker(&mut self, prob: &Problem, center: Point, skip: usize, delta: i32) {
+        for midx in 0..self.placements.len() {
+            if midx == skip {
+                continue;
+            }
+            let place = self.placements[midx];
+            let mut diff = 0;
+            for aidx in shadowed_attendees(&self.angles[midx], place, center) {
+                if !is_blocked_at(&prob.attendees[aidx], place, center) {
+                    continue;
+                }
+                if delta > 0 {
+                    if self.blocked[midx][aidx] == 0 {
+                        diff -= self.term(midx, aidx);
+                    }
+                    self.blocked[midx][aidx] += 1;
+                } else {
+                    self.blocked[midx][aidx] -= 1;
+                    if self.blocked[midx][aidx] == 0 {
+                        diff += self.term(midx, aidx);
+                    }
+                }
+            }
+            self.contribution[midx] += diff;
+            self.total += diff;
+        }
+    }
+
+    pub fn move_musician(&mut self, prob: &Problem, midx: usize, to: Point) {
+        let from = self.placements[midx];
+        self.update_blocker(prob, from, midx, -1);
+        self.placements[midx] = to;
+        self.update_blocker(prob, to, midx, 1);
+        self.reset_musician(prob, midx);
+        self.recompute_contribution(midx);
+        self.recompute_scalar_of_kind(prob, prob.musicians[midx]);
+    }
+
+    pub fn swap_musicians(&mut self, prob: &Problem, a: usize, b: usize) {
+        if a == b {
+            return;
+        }
+        // the set of occupied places does not change, so the blocking state moves with the place
+        self.placements.swap(a, b);
+        self.pillar_visible.swap(a, b);
+        self.blocked.swap(a, b);
+        self.angles.swap(a, b);
+        self.impacts.swap(a, b);
+        self.scalar.swap(a, b);
+        let (kind_a, kind_b) = (prob.musicians[a], prob.musicians[b]);
+        if kind_a != kind_b {
+            for midx in [a, b] {
+                let place = self.placements[midx];
+                let kind = prob.musicians[midx];
+                self.impacts[midx] = prob
+                    .attendees
+                    .iter()
+                    .map(|attendee| impact_raw(attendee, kind, place))
+                    .collect();
+            }
+        }
+        self.recompute_contribution(a);
+        self.recompute_contribution(b);
+        if kind_a != kind_b {
+            self.recompute_scalar_of_kind(prob, kind_a);
+            self.recompute_scalar_of_kind(prob, kind_b);
+        }
+    }
+
+    pub fn set_volume(&mut self, midx: usize, volume: f64) {
+        self.volumes[midx] = volume;
+        self.recompute_contribution(midx);
+    }
+
+    // Gradient of the total score with respect to the position of `midx`, with the set of
+    // blocked lines and the rounding of each term held fixed.
+    pub fn gradient(&self, prob: &Problem, midx: usize) -> Point {
+        let place = self.placements[midx];
+        let kind = prob.musicians[midx];
+        let mut grad = Point { x: 0.0, y: 0.0 };
+        for (aidx, attendee) in prob.attendees.iter().enumerate() {
+            if !self.pillar_visible[midx][aidx] || self.blocked[midx][aidx] > 0 {
+                continue;
+            }
+            // d/dp 1e6 * taste / |a - p|^2 = 2e6 * taste * (a - p) / |a - p|^4
+            let v = attendee.place() - place;
+            let d2 = v.norm();
+            grad = grad + (2e6 * attendee.tastes[kind as usize] / (d2 * d2)) * v;
+        }
+        grad = (self.volumes[midx] * self.scalar[midx]) * grad;
+        if is_full_division_scoring(prob) {
+            // both scalars of a same-kind pair have a 1/d term
+            let own = self.contribution[midx] as f64 / self.scalar[midx];
+            for (another, &p) in self.placements.iter().enumerate() {
+                if another == midx || prob.musicians[another] != kind {
+                    continue;
+                }
+                let other = self.contribution[another] as f64 / self.scalar[another];
+                let v = p - place;
+                grad = grad + ((own + other) / v.length().powi(3)) * v;
+            }
+        }
+        grad
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use rand::prelude::*;
+    use rand::rngs::SmallRng;
+
+    pub(crate) fn random_problem(rng: &mut SmallRng, with_pillars: bool) -> Problem {
+        let attendees = (0..60)
+            .map(|_| Attendee {
+                x: rng.gen_range(0.0..300.0),
+                y: rng.gen_range(0.0..300.0),
+                tastes: (0..3).map(|_| rng.gen_range(-1000.0..1000.0)).collect(),
+            })
+            .collect();
+        let pillars = if with_pillars {
+            vec![Pillar {
+                center: (50.0, 150.0),
+                radius: 10.0,
+            }]
+        } else {
+            vec![]
+        };
+        Problem {
+            room_width: 300.0,
+            room_height: 300.0,
+            stage_width: 100.0,
+            stage_height: 100.0,
+            stage_bottom_left: vec![100.0, 100.0],
+            musicians: (0..12).map(|i| i % 3).collect(),
+            attendees,
+            pillars,
+        }
+    }
+
+    pub(crate) fn random_place(
+        rng: &mut SmallRng,
+        placements: &[Point],
+        skip: usize,
+    ) -> Option<Point> {
+        let place = Point {
+            x: rng.gen_range(110.0..190.0),
+            y: rng.gen_range(110.0..190.0),
+        };
+        let valid = placements
+            .iter()
+            .enumerate()
+            .all(|(i, &p)| i == skip || (p - place).norm() >= 100.0);
+        valid.then_some(place)
+    }
+
+    pub(crate) fn random_placements(rng: &mut SmallRng, prob: &Problem) -> Vec<Point> {
+        let mut placements = Vec::new();
+        while placements.len() < prob.musicians.len() {
+            if let Some(p) = random_place(rng, &placements, usize::MAX) {
+                placements.push(p);
+            }
+        }
+        placements
+    }
+
+    #[test]
+    fn test_incremental_score() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        for with_pillars in [false, true] {
+            let prob = random_problem(&mut rng, with_pillars);
+            let placements = random_placements(&mut rng, &prob);
+            let sol = Solution {
+                placements,
+                volumes: vec![1.0; prob.musicians.len()],
+            };
+            let mut state = IncrementalScore::new(&prob, &sol);
+            assert_eq!(state.score(), score(&prob, &sol, true).unwrap());
+            for _ in 0..200 {
+                let midx = rng.gen_range(0..prob.musicians.len());
+                match rng.gen_range(0..3) {
+                    0 => {
+                        if let Some(p) = random_place(&mut rng, state.placements(), midx) {
+                            state.move_musician(&prob, midx, p);
+                        }
+                    }
+                    1 => {
+                        let another = rng.gen_range(0..prob.musicians.len());
+                        state.swap_musicians(&prob, midx, another);
+                    }
+                    _ => state.set_volume(midx, rng.gen_range(0.0..10.0)),
+                }
+                assert_eq!(
+                    state.score(),
+                    score(&prob, &state.solution(), true).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_gradient() {
+        let mut rng = SmallRng::seed_from_u64(2);
+        for with_pillars in [false, true] {
+            let prob = random_problem(&mut rng, with_pillars);
+            let placements = random_placements(&mut rng, &prob);
+            let sol = Solution {
+                placements,
+                volumes: vec![10.0; prob.musicians.len()],
+            };
+            let mut state = IncrementalScore::new(&prob, &sol);
+            let h = 0.1;
+            let mut checked = 0;
+            for midx in 0..prob.musicians.len() {
+                let grad = state.gradient(&prob, midx);
+                let place = state.placements()[midx];
+                for dir in [Point { x: h, y: 0.0 }, Point { x: 0.0, y: h }] {
+                    state.move_musician(&prob, midx, place + dir);
+                    let (plus, blocked_plus) = (state.score(), state.blocked.clone());
+                    state.move_musician(&prob, midx, place - dir);
+                    let (minus, blocked_minus) = (state.score(), state.blocked.clone());
+                    state.move_musician(&prob, midx, place);
+                    // the gradient does not see lines becoming blocked or unblocked
+                    if blocked_plus != blocked_minus {
+                        continue;
+                    }
+                    let numeric = (plus - minus) as f64 / (2.0 * h);
+                    let analytic = grad.dot(dir) / h;
+                    // every term is rounded up, on both sides of the difference
+                    let noise = prob.attendees.len() as f64 / h;
+                    assert!(
+                        (numeric - analytic).abs() <= 0.05 * analytic.abs() + noise,
+                        "{} {}",
+                        numeric,
+                        analytic
+                    );
+                    checked += 1;
+                }
+            }
+            assert!(checked > 0);
+        }
+    }
+}
@@ -1,21 +1,39 @@
+mod anneal;
+mod bitset;
 mod cache;
+mod checkpoint;
 mod climbing;
 mod common;
 mod geometry;
 mod greedy;
 mod hungarian;
+mod incremental;
 mod placement;
+mod polish;
+mod refine;
+mod ruin;
 mod score;
+mod spatial;
+mod visibility;
 mod visualize;
+mod volume;
+use crate::anneal::*;
+use crate::checkpoint::*;
 use crate::climbing::*;
 use crate::common::*;
 use crate::greedy::*;
 use crate::hungarian::*;
+use crate::placement::*;
+use crate::polish::*;
+use crate::refine::*;
+use crate::ruin::*;
 use crate::score::*;
 use crate::visualize::*;
+use crate::volume::*;
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 struct Cli {
@@ -23,20 +41,118 @@ struct Cli {
     command: Commands,
 }
 
+// Options shared by the commands that search for a solution from scratch.
+#[derive(Args)]
+struct SearchArgs {
+    // drop candidates that see less than this share of the audience past the pillars
+    #[arg(long, default_value_t = 0.0)]
+    shadow_threshold: f64,
+    // seconds between saves of the best solution so far
+    #[arg(long, default_value_t = 60.0)]
+    checkpoint_interval: f64,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     Solve {
         input: PathBuf,
         output: PathBuf,
+        #[command(flatten)]
+        search: SearchArgs,
+        // print the number of candidates and the cache size of every placement mode
+        #[arg(long)]
+        verbose: bool,
     },
     Climb {
         input: PathBuf,
         output: PathBuf,
+        #[arg(long, default_value_t = 100)]
+        iterations: usize,
+        // seconds for each placement mode
+        #[arg(long)]
+        time_limit: Option<f64>,
+        // musicians removed per iteration
+        #[arg(long)]
+        destruction: Option<usize>,
+        #[arg(long)]
+        seed: Option<u64>,
+        #[arg(long, value_delimiter = ',', default_value = "normal,corner")]
+        placement_modes: Vec<PlacementMode>,
+        #[arg(long)]
+        log: Option<PathBuf>,
+        // solution to start from
+        #[arg(long)]
+        init: Option<PathBuf>,
+        #[arg(
+            long,
+            value_enum,
+            value_delimiter = ',',
+            default_value = "random,cluster,lowest-contribution,one-kind,most-blocking"
+        )]
+        ruin_operators: Vec<RuinOperator>,
+        #[command(flatten)]
+        search: SearchArgs,
+        // checkpoint of an interrupted run to continue
+        #[arg(long, conflicts_with = "init")]
+        resume: Option<PathBuf>,
     },
     Optimize {
         problem: PathBuf,
         solution: PathBuf,
         output: PathBuf,
+        // also allow moving to up to this many empty grid positions; implies --repeat
+        #[arg(long)]
+        candidates: Option<usize>,
+        // redo the assignment until the score stops improving
+        #[arg(long)]
+        repeat: bool,
+    },
+    Refine {
+        problem: PathBuf,
+        solution: PathBuf,
+        output: PathBuf,
+        // musicians to refine around
+        #[arg(long, default_value_t = 20)]
+        top: usize,
+        #[arg(long, default_value_t = 5)]
+        rounds: usize,
+    },
+    OptimizeVolume {
+        problem: PathBuf,
+        solution: PathBuf,
+        output: PathBuf,
+    },
+    Anneal {
+        problem: PathBuf,
+        solution: PathBuf,
+        output: PathBuf,
+        // seconds
+        #[arg(long, default_value_t = 60.0)]
+        time_limit: f64,
+        #[arg(long, default_value_t = 1e5)]
+        start_temp: f64,
+        #[arg(long, default_value_t = 1e2)]
+        end_temp: f64,
+        #[arg(long, value_enum, default_value_t = Schedule::Exponential)]
+        schedule: Schedule,
+        #[arg(long)]
+        seed: Option<u64>,
+        #[arg(long, default_value_t = 2.0)]
+        step: f64,
+    },
+    Polish {
+        problem: PathBuf,
+        solution: PathBuf,
+        output: PathBuf,
+        #[arg(long, default_value_t = 100)]
+        iterations: usize,
+        #[arg(long, default_value_t = 1.0)]
+        step: f64,
+        #[arg(long, default_value_t = 0.01)]
+        min_step: f64,
+        // seconds
+        #[arg(long)]
+        time_limit: Option<f64>,
     },
     Visualize {
         problem: PathBuf,
@@ -47,6 +163,8 @@ enum Commands {
         problem: PathBuf,
         solution: PathBuf,
         quiet: Option<bool>,
+        #[arg(long)]
+        report: Option<PathBuf>,
     },
     Submit {
         id: u32,
@@ -70,24 +188,193 @@ enum Commands {
 fn main() -> Result<()> {
     let cli = Cli::parse();
     match &cli.command {
-        Commands::Solve { input, output } => {
+        Commands::Solve {
+            input,
+            output,
+            search,
+            verbose,
+        } => {
             let prob = Problem::load_from_file(input)?;
-            let sol = solve_greedy(&prob)?;
-            sol.save_to_file(output)?;
+            let checkpointer =
+                Checkpointer::new(output, Duration::from_secs_f64(search.checkpoint_interval))?;
+            // every solution found is reported to the checkpointer, which writes the best one
+            solve_greedy(&prob, search.shadow_threshold, *verbose, &checkpointer)?;
+            checkpointer.save()?;
         }
-        Commands::Climb { input, output } => {
+        Commands::Climb {
+            input,
+            output,
+            iterations,
+            time_limit,
+            destruction,
+            seed,
+            placement_modes,
+            log,
+            init,
+            ruin_operators,
+            search,
+            resume,
+        } => {
             let prob = Problem::load_from_file(input)?;
-            let sol = solve_climbing(&prob)?;
-            sol.save_to_file(output)?;
+            let mut init = match init {
+                Some(path) => Some(Solution::load_from_file(path)?),
+                None => None,
+            };
+            let checkpointer =
+                Checkpointer::new(output, Duration::from_secs_f64(search.checkpoint_interval))?;
+            let mut seed = *seed;
+            let mut completed = Vec::new();
+            let mut draws = Vec::new();
+            if let Some(path) = resume {
+                let checkpoint = Checkpoint::load_from_file(path)?;
+                eprintln!(
+                    "resume: score {} at iteration {}, {:?} iterations completed",
+                    checkpoint.score, checkpoint.iteration, checkpoint.completed
+                );
+                checkpointer.resume_from(&checkpoint);
+                // each placement mode runs only the iterations it has not completed yet
+                completed = checkpoint.completed.clone();
+                // the random streams continue where they were only with the seed they started from
+                if seed.is_none() || seed == checkpoint.seed {
+                    seed = checkpoint.seed;
+                    draws = checkpoint.draws.clone();
+                }
+                init = Some(checkpoint.solution);
+            }
+            let config = ClimbConfig {
+                iterations: *iterations,
+                time_limit: time_limit.map(Duration::from_secs_f64),
+                destruction: *destruction,
+                seed,
+                completed,
+                draws,
+                placement_modes: placement_modes.clone(),
+                log: log.clone(),
+                init,
+                ruin_operators: ruin_operators.clone(),
+                shadow_threshold: search.shadow_threshold,
+            };
+            solve_climbing(&prob, &config, &checkpointer)?;
+            checkpointer.save()?;
         }
         Commands::Optimize {
             problem,
             solution,
             output,
+            candidates,
+            repeat,
         } => {
             let prob = Problem::load_from_file(problem)?;
             let sol: Solution = Solution::load_from_file(solution)?;
-            let opt_sol = optimize_hungarian(&prob, &sol)?;
+            let result = match candidates {
+                Some(max_candidates) => optimize_hungarian_candidates(
+                    &prob,
+                    &sol,
+                    PlacementMode::GridNormal(InterpolateMode::Strech),
+                    *max_candidates,
+                )?,
+                None if is_full_division_scoring(&prob) => {
+                    optimize_hungarian_together(&prob, &sol, *repeat)?
+                }
+                None => optimize_hungarian(&prob, &sol, *repeat)?,
+            };
+            println!(
+                "score: {} -> {} ({:+})",
+                result.before,
+                result.after,
+                result.after - result.before
+            );
+            if result.after >= result.before {
+                result.solution.save_to_file(output)?;
+            } else {
+                sol.save_to_file(output)?;
+            }
+        }
+        Commands::Refine {
+            problem,
+            solution,
+            output,
+            top,
+            rounds,
+        } => {
+            let prob = Problem::load_from_file(problem)?;
+            let sol = Solution::load_from_file(solution)?;
+            let result = refine(
+                &prob,
+                &sol,
+                PlacementMode::GridNormal(InterpolateMode::Strech),
+                *top,
+                *rounds,
+            )?;
+            println!(
+                "score: {} -> {} ({:+})",
+                result.before,
+                result.after,
+                result.after - result.before
+            );
+            result.solution.save_to_file(output)?;
+        }
+        Commands::OptimizeVolume {
+            problem,
+            solution,
+            output,
+        } => {
+            let prob = Problem::load_from_file(problem)?;
+            let sol = Solution::load_from_file(solution)?;
+            let opt_sol = optimize_volume(&prob, &sol)?;
+            let before = score(&prob, &sol, true)?;
+            let after = score(&prob, &opt_sol, true)?;
+            println!("score: {} -> {} ({:+})", before, after, after - before);
+            opt_sol.save_to_file(output)?;
+        }
+        Commands::Anneal {
+            problem,
+            solution,
+            output,
+            time_limit,
+            start_temp,
+            end_temp,
+            schedule,
+            seed,
+            step,
+        } => {
+            let prob = Problem::load_from_file(problem)?;
+            let sol = Solution::load_from_file(solution)?;
+            let config = AnnealConfig {
+                start_temp: *start_temp,
+                end_temp: *end_temp,
+                schedule: *schedule,
+                time_limit: Duration::from_secs_f64(*time_limit),
+                seed: *seed,
+                step: *step,
+            };
+            let opt_sol = anneal(&prob, &sol, &config)?;
+            let before = score(&prob, &sol, true)?;
+            let after = score(&prob, &opt_sol, true)?;
+            println!("score: {} -> {} ({:+})", before, after, after - before);
+            opt_sol.save_to_file(output)?;
+        }
+        Commands::Polish {
+            problem,
+            solution,
+            output,
+            iterations,
+            step,
+            min_step,
+            time_limit,
+        } => {
+            let prob = Problem::load_from_file(problem)?;
+            let sol = Solution::load_from_file(solution)?;
+            let config = PolishConfig {
+                iterations: *iterations,
+                step: *step,
+                min_step: *min_step,
+                time_limit: time_limit.map(Duration::from_secs_f64),
+            };
+            let opt_sol = polish(&prob, &sol, &config)?;
+            let before = score(&prob, &sol, true)?;
+            let after = score(&prob, &opt_sol, true)?;
+            println!("score: {} -> {} ({:+})", before, after, after - before);
             opt_sol.save_to_file(output)?;
         }
         Commands::Visualize {
@@ -111,10 +398,18 @@ fn main() -> Result<()> {
             problem,
             solution,
             quiet,
+            report,
         } => {
             let prob = Problem::load_from_file(problem)?;
             let sol = Solution::load_from_file(solution)?;
-            let s = score(&prob, &sol, quiet.unwrap_or(false))?;
+            let s = match report {
+                Some(report) => {
+                    let breakdown = score_breakdown(&prob, &sol)?;
+                    breakdown.save_to_file(report)?;
+                    breakdown.total
+                }
+                None => score(&prob, &sol, quiet.unwrap_or(false))?,
+            };
             println!("score: {}", s);
         }
         Commands::Download {
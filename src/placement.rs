@@ -1,6 +1,12 @@
 use crate::common::*;
 use crate::geometry::*;
+use crate::score::*;
 use anyhow::Result;
+use rand::prelude::*;
+use rand::rngs::SmallRng;
+use rayon::prelude::*;
+use std::f64::consts::PI;
+use std::str::FromStr;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -9,17 +15,67 @@ pub enum GeneratePlacementError {
     LackCandidatesError,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub enum InterpolateMode {
     Strech,
     Corner(f64),
 }
 
-#[derive(Clone, Copy)]
+// Direction of the rows of a hexagonal lattice.
+#[derive(Clone, Copy, Debug)]
+pub enum HexOrientation {
+    Horizontal,
+    Vertical,
+}
+
+#[derive(Clone, Copy, Debug)]
 pub enum PlacementMode {
     GridNormal(InterpolateMode),
     GridDiag,
     GridCompress,
+    // shifts the even rows by half a spacing instead of the odd ones when the flag is set
+    Hex(HexOrientation, bool),
+    // random points with the given seed
+    PoissonDisk(u64),
+    // rows along the stage edges facing the audience, about this many candidates in total
+    // (three per musician when not given)
+    Edge(Option<usize>),
+}
+
+#[derive(Error, Debug)]
+#[error(
+    "Unknown placement mode: {0} (expected normal, corner, diag, compress, hex, hex-offset, \
+     hex-vertical, hex-vertical-offset, poisson, poisson:<seed>, edge or edge:<count>)"
+)]
+pub struct ParsePlacementModeError(String);
+
+// Names used on the command line.
+impl FromStr for PlacementMode {
+    type Err = ParsePlacementModeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(PlacementMode::GridNormal(InterpolateMode::Strech)),
+            "corner" => Ok(PlacementMode::GridNormal(InterpolateMode::Corner(10.0))),
+            "diag" => Ok(PlacementMode::GridDiag),
+            "compress" => Ok(PlacementMode::GridCompress),
+            "hex" => Ok(PlacementMode::Hex(HexOrientation::Horizontal, false)),
+            "hex-offset" => Ok(PlacementMode::Hex(HexOrientation::Horizontal, true)),
+            "hex-vertical" => Ok(PlacementMode::Hex(HexOrientation::Vertical, false)),
+            "hex-vertical-offset" => Ok(PlacementMode::Hex(HexOrientation::Vertical, true)),
+            "poisson" => Ok(PlacementMode::PoissonDisk(0)),
+            "edge" => Ok(PlacementMode::Edge(None)),
+            _ => {
+                if let Some(Ok(seed)) = s.strip_prefix("poisson:").map(str::parse) {
+                    Ok(PlacementMode::PoissonDisk(seed))
+                } else if let Some(Ok(count)) = s.strip_prefix("edge:").map(str::parse) {
+                    Ok(PlacementMode::Edge(Some(count)))
+                } else {
+                    Err(ParsePlacementModeError(s.to_string()))
+                }
+            }
+        }
+    }
 }
 
 fn interpolate(length: f64, total: usize, index: usize, mode: InterpolateMode) -> f64 {
@@ -122,7 +178,7 @@ fn generate_candidate_grid_compress(bottom_left: Point, size: Point) -> Result<V
         }
         let sub_height = (100. - sub_width * sub_width).sqrt();
         let rows = (size.y / sub_height).floor() as usize;
-        let count = (rows * cols + 1) / 2;
+        let count = (rows * cols).div_ceil(2);
         if count > best_count {
             best_count = count;
             best_cols = cols;
@@ -132,6 +188,277 @@ fn generate_candidate_grid_compress(bottom_left: Point, size: Point) -> Result<V
     generate_candidate_checker(bottom_left, size, best_rows, best_cols)
 }
 
+// A little over 5√3, so that rounding never brings neighbors in adjacent shifted rows closer
+// than 10.
+const HEX_ROW_DISTANCE: f64 = 8.6603;
+
+// Positions along a row of the given length, at least 10 apart and stretched to reach both
+// ends. A shifted row takes the midpoints instead.
+fn row_positions(length: f64, shifted: bool) -> Vec<f64> {
+    let min_distance = 10.0;
+    let cols = (length / min_distance).floor() as usize + 1;
+    if !shifted {
+        if cols == 1 {
+            return vec![0.0];
+        }
+        return (0..cols)
+            .map(|col| length * col as f64 / (cols - 1) as f64)
+            .collect();
+    }
+    let col_distance = if cols > 1 {
+        length / (cols - 1) as f64
+    } else {
+        min_distance
+    };
+    (0..cols)
+        .map(|col| col as f64 * col_distance + col_distance / 2.0)
+        .take_while(|&x| x <= length)
+        .collect()
+}
+
+// Rows 10 apart along x and about 5√3 apart along y, stretched to reach all four edges, with
+// every other row shifted by half a spacing.
+fn generate_candidate_hex(bottom_left: Point, size: Point, shift_even: bool) -> Vec<Point> {
+    let rows = (size.y / HEX_ROW_DISTANCE).floor() as usize + 1;
+    let mut placement_candidates = Vec::new();
+    for row in 0..rows {
+        let y = if rows > 1 {
+            size.y * row as f64 / (rows - 1) as f64
+        } else {
+            0.0
+        };
+        let shifted = (row % 2 == 0) == shift_even;
+        for x in row_positions(size.x, shifted) {
+            placement_candidates.push(bottom_left + Point { x, y });
+        }
+    }
+    placement_candidates
+}
+
+fn generate_candidate_hex_oriented(
+    bottom_left: Point,
+    size: Point,
+    orientation: HexOrientation,
+    shift_even: bool,
+) -> Result<Vec<Point>> {
+    Ok(match orientation {
+        HexOrientation::Horizontal => generate_candidate_hex(bottom_left, size, shift_even),
+        HexOrientation::Vertical => {
+            let transposed = |p: Point| Point { x: p.y, y: p.x };
+            generate_candidate_hex(transposed(bottom_left), transposed(size), shift_even)
+                .into_iter()
+                .map(transposed)
+                .collect()
+        }
+    })
+}
+
+// Bridson's algorithm: random points at least 10 apart, until no more fit.
+fn generate_candidate_poisson_disk(
+    bottom_left: Point,
+    size: Point,
+    seed: u64,
+) -> Result<Vec<Point>> {
+    let min_distance = 10.0;
+    // the longest new samples can be from the point they are drawn around
+    let max_distance = 2.0 * min_distance;
+    let attempts = 30;
+    // each cell holds at most one point
+    let cell = min_distance / 2f64.sqrt();
+    let cols = (size.x / cell).floor() as usize + 1;
+    let rows = (size.y / cell).floor() as usize + 1;
+    let cell_of = |p: Point| ((p.x / cell) as usize, (p.y / cell) as usize);
+    let mut grid: Vec<Option<usize>> = vec![None; cols * rows];
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut points = Vec::new();
+    let mut active = Vec::new();
+
+    let fits = |grid: &[Option<usize>], points: &[Point], p: Point| {
+        if p.x < 0.0 || p.x > size.x || p.y < 0.0 || p.y > size.y {
+            return false;
+        }
+        let (cx, cy) = cell_of(p);
+        for y in cy.saturating_sub(2)..(cy + 3).min(rows) {
+            for x in cx.saturating_sub(2)..(cx + 3).min(cols) {
+                if let Some(idx) = grid[y * cols + x] {
+                    if (points[idx] - p).norm() < min_distance * min_distance {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    };
+
+    let first = Point {
+        x: rng.gen_range(0.0..=size.x),
+        y: rng.gen_range(0.0..=size.y),
+    };
+    let (cx, cy) = cell_of(first);
+    grid[cy * cols + cx] = Some(0);
+    points.push(first);
+    active.push(0);
+    while !active.is_empty() {
+        let i = rng.gen_range(0..active.len());
+        let center = points[active[i]];
+        let found = (0..attempts)
+            .map(|_| {
+                let angle = rng.gen_range(-PI..PI);
+                let length = rng.gen_range(min_distance..max_distance);
+                center
+                    + Point {
+                        x: length * angle.cos(),
+                        y: length * angle.sin(),
+                    }
+            })
+            .find(|&p| fits(&grid, &points, p));
+        match found {
+            Some(p) => {
+                let (cx, cy) = cell_of(p);
+                grid[cy * cols + cx] = Some(points.len());
+                active.push(points.len());
+                points.push(p);
+            }
+            None => {
+                active.swap_remove(i);
+            }
+        }
+    }
+    Ok(points.into_iter().map(|p| bottom_left + p).collect())
+}
+
+// One side of the area where musicians can stand.
+struct StageEdge {
+    // outer edge of the stage
+    edge: Line,
+    // first point of the outermost row
+    origin: Point,
+    // unit vectors along the rows and towards the center
+    along: Point,
+    inward: Point,
+    length: f64,
+    depth: f64,
+}
+
+fn stage_edges(bottom_left: Point, size: Point) -> [StageEdge; 4] {
+    let padding = 10.0;
+    let top_right = bottom_left + size;
+    let top_left = Point {
+        x: bottom_left.x,
+        y: top_right.y,
+    };
+    let bottom_right = Point {
+        x: top_right.x,
+        y: bottom_left.y,
+    };
+    let outward = |p: Point, direction: Point| p - padding * direction;
+    let right = Point { x: 1.0, y: 0.0 };
+    let up = Point { x: 0.0, y: 1.0 };
+    let left = Point { x: -1.0, y: 0.0 };
+    let down = Point { x: 0.0, y: -1.0 };
+    [
+        StageEdge {
+            edge: Line {
+                p1: outward(bottom_left, up),
+                p2: outward(bottom_right, up),
+            },
+            origin: bottom_left,
+            along: right,
+            inward: up,
+            length: size.x,
+            depth: size.y,
+        },
+        StageEdge {
+            edge: Line {
+                p1: outward(top_left, down),
+                p2: outward(top_right, down),
+            },
+            origin: top_left,
+            along: right,
+            inward: down,
+            length: size.x,
+            depth: size.y,
+        },
+        StageEdge {
+            edge: Line {
+                p1: outward(bottom_left, right),
+                p2: outward(top_left, right),
+            },
+            origin: bottom_left,
+            along: up,
+            inward: right,
+            length: size.y,
+            depth: size.x,
+        },
+        StageEdge {
+            edge: Line {
+                p1: outward(bottom_right, left),
+                p2: outward(top_right, left),
+            },
+            origin: bottom_right,
+            along: up,
+            inward: left,
+            length: size.y,
+            depth: size.x,
+        },
+    ]
+}
+
+// Sum over the attendees in front of the edge of their positive tastes, weighted by 1/d² like
+// the impact.
+fn edge_weight(prob: &Problem, edge: &StageEdge) -> f64 {
+    prob.attendees
+        .iter()
+        .filter(|attendee| (attendee.place() - edge.edge.p1).dot(edge.inward) < 0.0)
+        .map(|attendee| {
+            let taste: f64 = attendee.tastes.iter().map(|&t| t.max(0.0)).sum();
+            taste / norm_segment_point(edge.edge, attendee.place()).max(1.0)
+        })
+        .sum()
+}
+
+// Rows parallel to each edge, 10 apart within a row and hexagonally shifted from one row to the
+// next so that inner rows see through the gaps of the rows in front. Rows are handed out one at
+// a time to the edge with the highest weight per row it already has, so edges facing more of
+// the audience get deeper.
+fn generate_candidate_edge(
+    prob: &Problem,
+    bottom_left: Point,
+    size: Point,
+    count: usize,
+) -> Vec<Point> {
+    let edges = stage_edges(bottom_left, size);
+    let mut weights: Vec<_> = edges.iter().map(|edge| edge_weight(prob, edge)).collect();
+    if weights.iter().all(|&w| w <= 0.0) {
+        weights = vec![1.0; edges.len()];
+    }
+    let mut rows = vec![0; edges.len()];
+    let mut placement_candidates: Vec<Point> = Vec::new();
+    while placement_candidates.len() < count {
+        let next = (0..edges.len())
+            .filter(|&e| weights[e] > 0.0 && rows[e] as f64 * HEX_ROW_DISTANCE <= edges[e].depth)
+            .max_by(|&a, &b| {
+                (weights[a] / (rows[a] + 1) as f64).total_cmp(&(weights[b] / (rows[b] + 1) as f64))
+            });
+        let Some(e) = next else { break; };
+        let edge = &edges[e];
+        let offset = edge.origin + (rows[e] as f64 * HEX_ROW_DISTANCE) * edge.inward;
+        for x in row_positions(edge.length, rows[e] % 2 == 1) {
+            let p = offset + x * edge.along;
+            // rows of different edges cross near the corners and in the middle
+            if placement_candidates
+                .iter()
+                .all(|&q| (p - q).norm() >= 100.0)
+            {
+                placement_candidates.push(p);
+            }
+        }
+        rows[e] += 1;
+    }
+    placement_candidates.truncate(count);
+    placement_candidates
+}
+
 pub fn generate_candidates(prob: &Problem, mode: PlacementMode) -> Result<Vec<Point>> {
     let padding = 10.0;
     let bottom_left = prob.stage_from()
@@ -148,9 +475,198 @@ pub fn generate_candidates(prob: &Problem, mode: PlacementMode) -> Result<Vec<Po
         PlacementMode::GridNormal(mode) => generate_candidate_grid_normal(bottom_left, size, mode)?,
         PlacementMode::GridDiag => generate_candidate_grid_diag(bottom_left, size)?,
         PlacementMode::GridCompress => generate_candidate_grid_compress(bottom_left, size)?,
+        PlacementMode::Hex(orientation, shift_even) => {
+            generate_candidate_hex_oriented(bottom_left, size, orientation, shift_even)?
+        }
+        PlacementMode::PoissonDisk(seed) => {
+            generate_candidate_poisson_disk(bottom_left, size, seed)?
+        }
+        PlacementMode::Edge(count) => {
+            let count = count.unwrap_or(3 * prob.musicians.len());
+            generate_candidate_edge(prob, bottom_left, size, count)
+        }
     };
     if placement_candidates.len() < prob.musicians.len() {
         return Err(GeneratePlacementError::LackCandidatesError.into());
     }
     Ok(placement_candidates)
 }
+
+// Share of the audience weight of each candidate that no pillar hides. An attendee weighs the
+// best positive impact over the kinds at the candidate.
+fn visible_weight_ratios(prob: &Problem, candidates: &[Point]) -> Vec<f64> {
+    candidates
+        .par_iter()
+        .map(|&place| {
+            let visible = visible_attendees_from(prob, place, std::iter::empty());
+            let (mut total, mut seen) = (0, 0);
+            for (attendee, vis) in prob.attendees.iter().zip(visible) {
+                let weight = (0..attendee.tastes.len())
+                    .map(|kind| impact_raw(attendee, kind as u32, place))
+                    .max()
+                    .unwrap_or(0)
+                    .max(0);
+                total += weight;
+                if vis {
+                    seen += weight;
+                }
+            }
+            if total == 0 {
+                0.0
+            } else {
+                seen as f64 / total as f64
+            }
+        })
+        .collect()
+}
+
+// Drops the candidates for which pillars hide all but less than `threshold` of the audience
+// weight. The most visible candidates are always kept, enough for all musicians.
+pub fn prune_shadowed_candidates(
+    prob: &Problem,
+    candidates: Vec<Point>,
+    threshold: f64,
+) -> Vec<Point> {
+    if prob.pillars.is_empty() || threshold <= 0.0 || candidates.is_empty() {
+        return candidates;
+    }
+    let ratios = visible_weight_ratios(prob, &candidates);
+    let mut sorted = ratios.clone();
+    sorted.sort_unstable_by(|a, b| b.total_cmp(a));
+    let mut cutoff = threshold;
+    if let Some(&ratio) = sorted.get(prob.musicians.len().max(1) - 1) {
+        cutoff = cutoff.min(ratio);
+    }
+    let total = candidates.len();
+    let kept: Vec<_> = candidates
+        .into_iter()
+        .zip(ratios)
+        .filter(|&(_, ratio)| ratio >= cutoff)
+        .map(|(place, _)| place)
+        .collect();
+    eprintln!(
+        "pillar shadows: pruned {} of {} candidates",
+        total - kept.len(),
+        total
+    );
+    kept
+}
+
+// The given placements first, then the candidates that keep 10 units from all of them, so any
+// choice of distinct points from the result is a valid placement.
+pub fn merge_candidates(placements: &[Point], candidates: &[Point]) -> Vec<Point> {
+    let mut merged = placements.to_vec();
+    merged.extend(
+        candidates
+            .iter()
+            .filter(|&&c| placements.iter().all(|&p| (c - p).norm() >= 100.0)),
+    );
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_candidates_spacing() {
+        let modes = [
+            PlacementMode::Hex(HexOrientation::Horizontal, false),
+            PlacementMode::Hex(HexOrientation::Horizontal, true),
+            PlacementMode::Hex(HexOrientation::Vertical, false),
+            PlacementMode::Hex(HexOrientation::Vertical, true),
+            PlacementMode::PoissonDisk(0),
+            PlacementMode::PoissonDisk(1),
+            PlacementMode::Edge(Some(50)),
+            PlacementMode::Edge(Some(10000)),
+        ];
+        for (width, height) in [(20.0, 20.0), (27.0, 95.5), (133.3, 41.0), (100.0, 100.0)] {
+            let prob = Problem {
+                room_width: 300.0,
+                room_height: 300.0,
+                stage_width: width,
+                stage_height: height,
+                stage_bottom_left: vec![13.5, 40.0],
+                musicians: Vec::new(),
+                attendees: Vec::new(),
+                pillars: Vec::new(),
+            };
+            for mode in modes {
+                let candidates = generate_candidates(&prob, mode).unwrap();
+                for (i, &p) in candidates.iter().enumerate() {
+                    assert!(
+                        p.x >= 13.5 + 10.0 && p.x <= 13.5 + width - 10.0,
+                        "{:?}",
+                        mode
+                    );
+                    assert!(
+                        p.y >= 40.0 + 10.0 && p.y <= 40.0 + height - 10.0,
+                        "{:?}",
+                        mode
+                    );
+                    for &q in &candidates[..i] {
+                        assert!((p - q).norm() >= 100.0, "{:?}: {:?} {:?}", mode, p, q);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_edge_faces_audience() {
+        let prob = Problem {
+            room_width: 300.0,
+            room_height: 300.0,
+            stage_width: 100.0,
+            stage_height: 100.0,
+            stage_bottom_left: vec![100.0, 100.0],
+            musicians: vec![0; 10],
+            attendees: (0..10)
+                .map(|i| Attendee {
+                    x: 100.0 + 10.0 * i as f64,
+                    y: 50.0,
+                    tastes: vec![1000.0],
+                })
+                .collect(),
+            pillars: Vec::new(),
+        };
+        let candidates = generate_candidates(&prob, PlacementMode::Edge(Some(40))).unwrap();
+        assert_eq!(candidates.len(), 40);
+        // the whole front row along the bottom edge comes first
+        assert!(candidates[..9].iter().all(|p| p.y == 110.0));
+        let below = candidates.iter().filter(|p| p.y < 150.0).count();
+        assert!(below > 30);
+    }
+
+    #[test]
+    fn test_prune_shadowed_candidates() {
+        let prob = Problem {
+            room_width: 300.0,
+            room_height: 300.0,
+            stage_width: 100.0,
+            stage_height: 100.0,
+            stage_bottom_left: vec![100.0, 100.0],
+            musicians: vec![0; 2],
+            attendees: (0..10)
+                .map(|i| Attendee {
+                    x: 145.0 + i as f64,
+                    y: 20.0,
+                    tastes: vec![1000.0],
+                })
+                .collect(),
+            pillars: vec![Pillar {
+                center: (150.0, 80.0),
+                radius: 10.0,
+            }],
+        };
+        let hidden = Point { x: 150.0, y: 110.0 };
+        let open = Point { x: 110.0, y: 110.0 };
+        let candidates = vec![hidden, open, Point { x: 190.0, y: 190.0 }];
+        let kept = prune_shadowed_candidates(&prob, candidates.clone(), 0.5);
+        assert_eq!(kept.len(), 2);
+        assert!(kept.iter().all(|p| (*p - hidden).norm() > 0.0));
+        // never fewer candidates than musicians
+        let kept = prune_shadowed_candidates(&prob, candidates[..2].to_vec(), 0.5);
+        assert_eq!(kept.len(), 2);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::common::*;
+use crate::geometry::*;
+use crate::incremental::*;
+use crate::score::*;
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+// Rounds of pushing a point out of the circles of its neighbours before giving up on a step.
+const PROJECT_ROUNDS: usize = 5;
+// Step lengths grow by this factor after an accepted step, and shrink by it after a rejected one.
+const STEP_GROWTH: f64 = 1.5;
+const STEP_SHRINK: f64 = 0.5;
+
+pub struct PolishConfig {
+    pub iterations: usize,
+    // initial length of a step
+    pub step: f64,
+    // musicians stop moving once their step is shorter than this
+    pub min_step: f64,
+    pub time_limit: Option<Duration>,
+}
+
+// Brings `place` back inside the stage margin and out of the 10-unit circles of the other
+// musicians, or gives up when pushing it out of one circle keeps landing in another.
+fn project(prob: &Problem, placements: &[Point], midx: usize, place: Point) -> Option<Point> {
+    let from = prob.stage_from();
+    let to = prob.stage_to();
+    let mut p = place;
+    for _ in 0..PROJECT_ROUNDS {
+        p.x = p.x.clamp(from.x + 10., to.x - 10.);
+        p.y = p.y.clamp(from.y + 10., to.y - 10.);
+        if is_feasible_place(prob, placements, midx, p) {
+            return Some(p);
+        }
+        for (idx, &q) in placements.iter().enumerate() {
+            let norm = (p - q).norm();
+            if idx == midx || norm >= 100.0 || norm == 0.0 {
+                continue;
+            }
+            p = q + (10.0 + 1e-6) * (p - q).normalize();
+        }
+    }
+    None
+}
+
+// Moves every musician in turn a step along the gradient of the score, projected back into the
+// feasible region. A step is kept only when the exact score increases, so changes of blocking
+// that the gradient does not see are accounted for. Each musician has its own step length.
+pub fn polish(prob: &Problem, sol: &Solution, config: &PolishConfig) -> Result<Solution> {
+    ensure_valid(prob, sol)?;
+    let m = prob.musicians.len();
+    let mut state = IncrementalScore::new(prob, sol);
+    let mut steps = vec![config.step; m];
+    let start = Instant::now();
+    for _ in 0..config.iterations {
+        if config
+            .time_limit
+            .is_some_and(|limit| start.elapsed() >= limit)
+        {
+            break;
+        }
+        for (midx, step) in steps.iter_mut().enumerate() {
+            if *step < config.min_step {
+                continue;
+            }
+            let grad = state.gradient(prob, midx);
+            if grad.norm() == 0.0 {
+                *step = 0.0;
+                continue;
+            }
+            let from = state.placements()[midx];
+            let target = from + *step * grad.normalize();
+            let Some(to) = project(prob, state.placements(), midx, target) else {
+                *step *= STEP_SHRINK;
+                continue;
+            };
+            let before = state.score();
+            state.move_musician(prob, midx, to);
+            if state.score() > before {
+                *step *= STEP_GROWTH;
+            } else {
+                state.move_musician(prob, midx, from);
+                *step *= STEP_SHRINK;
+            }
+        }
+        if steps.iter().all(|&step| step < config.min_step) {
+            break;
+        }
+    }
+    Ok(state.solution())
+}
@@ -0,0 +1,143 @@
+use crate::cache::*;
+use crate::common::*;
+use crate::geometry::*;
+use crate::hungarian::*;
+use crate::placement::*;
+use crate::score::*;
+use anyhow::Result;
+
+// Sub-grid points are tried on this step within 10 of a good position on both axes.
+const SUB_GRID_STEP: f64 = 2.5;
+// Only neighbours closer than this to a good position get tangent points.
+const TANGENT_RANGE: f64 = 30.0;
+// Refined points closer than this to a place or to another refined point are duplicates.
+const DUPLICATE_DISTANCE: f64 = 1e-6;
+
+// Points around the placement of musician `midx` that the lattice misses: a denser sub-grid,
+// and points on the lines through the placement that touch the blocking circle of a neighbour,
+// from where lines of sight just pass by it.
+fn refined_points(placements: &[Point], midx: usize) -> Vec<Point> {
+    let place = placements[midx];
+    let steps = (10.0 / SUB_GRID_STEP) as i32;
+    let mut points = Vec::new();
+    for i in -steps..=steps {
+        for j in -steps..=steps {
+            if i == 0 && j == 0 {
+                continue;
+            }
+            points.push(
+                place
+                    + Point {
+                        x: i as f64 * SUB_GRID_STEP,
+                        y: j as f64 * SUB_GRID_STEP,
+                    },
+            );
+        }
+    }
+    for (idx, &another) in placements.iter().enumerate() {
+        let d = (another - place).length();
+        if idx == midx || d >= TANGENT_RANGE {
+            continue;
+        }
+        let toward = (another - place).normalize();
+        let angle = (5.0 / d).asin();
+        for side in [-1.0, 1.0] {
+            let (sin, cos) = (side * angle).sin_cos();
+            let direction = Point {
+                x: toward.x * cos - toward.y * sin,
+                y: toward.x * sin + toward.y * cos,
+            };
+            for t in [-10.0, -5.0, 5.0, 10.0] {
+                points.push(place + t * direction);
+            }
+        }
+    }
+    points
+}
+
+// Each round takes the `top` best contributing musicians off the stage one at a time and places
+// each again greedily with DiffCache, over the candidates of `mode` merged with the refined points
+// around their placements. The result goes through optimize_hungarian_together. Rounds continue
+// while the score improves.
+pub fn refine(
+    prob: &Problem,
+    sol: &Solution,
+    mode: PlacementMode,
+    top: usize,
+    rounds: usize,
+) -> Result<HungarianResult> {
+    ensure_valid(prob, sol)?;
+    let m = prob.musicians.len();
+    let candidates = generate_candidates(prob, mode)?;
+    let (from, to) = (prob.stage_from(), prob.stage_to());
+    let on_stage = |p: &Point| {
+        p.x >= from.x + 10.0 && p.x <= to.x - 10.0 && p.y >= from.y + 10.0 && p.y <= to.y - 10.0
+    };
+    let before = score(prob, sol, true)?;
+    let mut best = sol.clone();
+    let mut best_score = before;
+    for _ in 0..rounds {
+        let breakdown = score_breakdown(prob, &best)?;
+        let mut order: Vec<_> = (0..m).collect();
+        order.sort_by_key(|&midx| std::cmp::Reverse(breakdown.musicians[midx].contribution));
+        order.truncate(top);
+
+        // musician k starts at place k; refined points next to a musician are only taken after
+        // it has left
+        let mut places = merge_candidates(&best.placements, &candidates);
+        let mut refined: Vec<Point> = Vec::new();
+        for point in order
+            .iter()
+            .flat_map(|&midx| refined_points(&best.placements, midx))
+            .filter(on_stage)
+        {
+            if places
+                .iter()
+                .chain(&refined)
+                .all(|&q| (point - q).length() >= DUPLICATE_DISTANCE)
+            {
+                refined.push(point);
+            }
+        }
+        places.extend(&refined);
+        let musician_to_place: Vec<_> = (0..m).map(Some).collect();
+        let mut place_to_musician = vec![None; places.len()];
+        for (k, e) in place_to_musician.iter_mut().take(m).enumerate() {
+            *e = Some(k);
+        }
+        let mut volumes = best.volumes.clone();
+        let mut cache = DiffCache::new(
+            prob,
+            &places,
+            &musician_to_place,
+            &place_to_musician,
+            &volumes,
+        );
+        for &midx in &order {
+            let pidx = cache.musician_to_place[midx].unwrap();
+            cache.remove_matching(prob, pidx, &volumes);
+            let (i, j, _d, v) = cache.find_best_matching();
+            volumes[j] = v;
+            cache.add_matching(prob, i, j, &volumes);
+        }
+        let next = Solution {
+            placements: cache
+                .musician_to_place
+                .iter()
+                .map(|e| places[e.unwrap()])
+                .collect(),
+            volumes,
+        };
+        let result = optimize_hungarian_together(prob, &next, false)?;
+        if result.after <= best_score {
+            break;
+        }
+        best = result.solution;
+        best_score = result.after;
+    }
+    Ok(HungarianResult {
+        solution: best,
+        before,
+        after: best_score,
+    })
+}
@@ -0,0 +1,192 @@
+use crate::common::*;
+use crate::geometry::*;
+use crate::score::*;
+use crate::spatial::*;
+use clap::ValueEnum;
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+use rayon::prelude::*;
+
+// How fast the weights follow recent results, and the reward for each outcome.
+const REACTION: f64 = 0.1;
+const REWARD_BEST: f64 = 10.0;
+const REWARD_NONE: f64 = 1.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RuinOperator {
+    // uniformly random musicians
+    Random,
+    // the musicians nearest to a random point on the stage
+    Cluster,
+    // among the musicians contributing the least
+    LowestContribution,
+    // musicians of one random kind
+    OneKind,
+    // among the musicians blocking the most lines of others
+    MostBlocking,
+}
+
+// Picks up to `size` musicians to take off the stage. `placements` and `contributions` describe
+// the current assignment, where every musician is placed.
+pub fn ruin(
+    op: RuinOperator,
+    prob: &Problem,
+    placements: &[Point],
+    contributions: &[i64],
+    size: usize,
+    rng: &mut impl Rng,
+) -> Vec<usize> {
+    let m = placements.len();
+    match op {
+        RuinOperator::Random => (0..m).choose_multiple(rng, size),
+        RuinOperator::Cluster => {
+            let from = prob.stage_from();
+            let to = prob.stage_to();
+            let center = Point {
+                x: rng.gen_range(from.x..=to.x),
+                y: rng.gen_range(from.y..=to.y),
+            };
+            let mut order: Vec<_> = (0..m).collect();
+            order.sort_by(|&a, &b| {
+                (placements[a] - center)
+                    .norm()
+                    .total_cmp(&(placements[b] - center).norm())
+            });
+            order.truncate(size);
+            order
+        }
+        RuinOperator::LowestContribution => {
+            let mut order: Vec<_> = (0..m).collect();
+            order.sort_by_key(|&midx| contributions[midx]);
+            pick_from_top(&order, size, rng)
+        }
+        RuinOperator::OneKind => {
+            let Some(&kind) = prob.musicians.choose(rng) else { return Vec::new(); };
+            (0..m)
+                .filter(|&midx| prob.musicians[midx] == kind)
+                .choose_multiple(rng, size)
+        }
+        RuinOperator::MostBlocking => {
+            let blocking = blocked_lines(prob, placements);
+            let mut order: Vec<_> = (0..m).collect();
+            order.sort_by_key(|&midx| std::cmp::Reverse(blocking[midx]));
+            pick_from_top(&order, size, rng)
+        }
+    }
+}
+
+// Random `size` of the first 2 * `size` in `order`, so that repeated calls do not always
+// remove the same musicians.
+fn pick_from_top(order: &[usize], size: usize, rng: &mut impl Rng) -> Vec<usize> {
+    let pool = &order[..order.len().min(2 * size)];
+    pool.choose_multiple(rng, size).cloned().collect()
+}
+
+// Number of lines from other musicians to attendees that each musician blocks.
+fn blocked_lines(prob: &Problem, placements: &[Point]) -> Vec<usize> {
+    let grid = SpatialGrid::new(placements, 5.0);
+    placements
+        .par_iter()
+        .enumerate()
+        .map(|(midx, &place)| {
+            let mut counts = vec![0; placements.len()];
+            let mut hit = Vec::new();
+            for attendee in prob.attendees.iter() {
+                hit.clear();
+                grid.any_near_segment(place, attendee.place(), |idx| {
+                    if idx != midx && is_blocked_by_another(attendee, place, placements[idx]) {
+                        hit.push(idx);
+                    }
+                    false
+                });
+                hit.sort_unstable();
+                hit.dedup();
+                for &idx in &hit {
+                    counts[idx] += 1;
+                }
+            }
+            counts
+        })
+        .reduce(
+            || vec![0; placements.len()],
+            |mut a, b| {
+                a.iter_mut().zip(b).for_each(|(x, y)| *x += y);
+                a
+            },
+        )
+}
+
+// Chooses operators with probability proportional to their weights, which move towards the
+// reward of each use.
+pub struct RuinWeights {
+    operators: Vec<RuinOperator>,
+    weights: Vec<f64>,
+    uses: Vec<usize>,
+    improvements: Vec<usize>,
+}
+
+impl RuinWeights {
+    pub fn new(operators: &[RuinOperator]) -> RuinWeights {
+        let n = operators.len();
+        RuinWeights {
+            operators: operators.to_vec(),
+            weights: vec![1.0; n],
+            uses: vec![0; n],
+            improvements: vec![0; n],
+        }
+    }
+
+    pub fn choose(&self, rng: &mut impl Rng) -> (usize, RuinOperator) {
+        let idx = WeightedIndex::new(&self.weights).unwrap().sample(rng);
+        (idx, self.operators[idx])
+    }
+
+    pub fn reward(&mut self, idx: usize, improved: bool) {
+        let reward = if improved { REWARD_BEST } else { REWARD_NONE };
+        self.weights[idx] = (1.0 - REACTION) * self.weights[idx] + REACTION * reward;
+        self.uses[idx] += 1;
+        if improved {
+            self.improvements[idx] += 1;
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        self.operators
+            .iter()
+            .enumerate()
+            .map(|(idx, op)| {
+                format!(
+                    "{:?}: uses={} improvements={} weight={:.2}",
+                    op, self.uses[idx], self.improvements[idx], self.weights[idx]
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::incremental::test::{random_placements, random_problem};
+    use rand::rngs::SmallRng;
+
+    #[test]
+    fn test_blocked_lines() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let prob = random_problem(&mut rng, false);
+        let placements = random_placements(&mut rng, &prob);
+        let mut expected = vec![0; placements.len()];
+        for (midx, &place) in placements.iter().enumerate() {
+            for attendee in prob.attendees.iter() {
+                for (idx, &another) in placements.iter().enumerate() {
+                    if idx != midx && is_blocked_by_another(attendee, place, another) {
+                        expected[idx] += 1;
+                    }
+                }
+            }
+        }
+        assert!(expected.iter().any(|&count| count > 0));
+        assert_eq!(expected, blocked_lines(&prob, &placements));
+    }
+}
@@ -1,7 +1,14 @@
 use crate::common::*;
 use crate::geometry::*;
+use crate::visibility::*;
 use anyhow::Result;
 use indicatif::ProgressBar;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use thiserror::Error;
 
 use crate::common::{Problem, Solution};
 
@@ -39,141 +46,333 @@ pub fn check_pillars(attendee: &Attendee, place: Point, pillars: &[Pillar]) -> b
     true
 }
 
-pub fn check_other_musicians(
-    attendee: &Attendee,
-    placements: &[Point],
-    musician_idx: usize,
-) -> bool {
-    let place = placements[musician_idx];
-    let musician_attendee_line = Line {
-        p1: attendee.place(),
-        p2: place,
-    };
-    for (check_musician_idx, check_place) in placements.iter().enumerate() {
-        if musician_idx == check_musician_idx {
-            continue;
-        }
-        let check_musician_area = Circle {
-            c: *check_place,
-            r: 5.0,
-        };
-        if is_cross_line_circle(musician_attendee_line, check_musician_area) {
-            return false;
-        }
-    }
-    true
+fn blocking_circles(others: impl Iterator<Item = Point>, pillars: &[Pillar]) -> Vec<Circle> {
+    others
+        .map(|c| Circle { c, r: 5.0 })
+        .chain(pillars.iter().map(|pillar| Circle {
+            c: pillar.c(),
+            r: pillar.radius,
+        }))
+        .collect()
 }
 
-pub fn impact(
-    attendee: &Attendee,
-    kinds: &[u32],
-    placements: &[Point],
-    musician_idx: usize,
-    pillars: &[Pillar],
-) -> i64 {
-    if !check_other_musicians(attendee, placements, musician_idx) {
-        return 0;
-    }
-    let place = placements[musician_idx];
-    if !check_pillars(attendee, place, pillars) {
-        return 0;
-    }
-    let kind = kinds[musician_idx];
-    impact_raw(attendee, kind, place)
-}
-
-pub fn happiness(
-    attendee: &Attendee,
-    musicians: &[u32],
-    pillars: &[Pillar],
-    sol: &Solution,
-    scalar: &[f64],
-) -> i64 {
-    let mut score = 0;
-    for (musician_idx, scale) in scalar.iter().enumerate() {
-        score += (sol.volumes[musician_idx]
-            * impact(attendee, &musicians, &sol.placements, musician_idx, pillars) as f64
-            * scale)
-            .ceil() as i64;
-    }
-    score
+fn others(placements: &[Point], musician_idx: usize) -> impl Iterator<Item = Point> + '_ {
+    placements
+        .iter()
+        .enumerate()
+        .filter(move |&(idx, _)| idx != musician_idx)
+        .map(|(_, &p)| p)
 }
 
-pub fn is_full_division_scoring(prob: &Problem) -> bool {
-    !prob.pillars.is_empty()
+// Attendees that can see `place` when musicians stand at `others`, taking pillars into account.
+pub fn visible_attendees_from(
+    prob: &Problem,
+    place: Point,
+    others: impl Iterator<Item = Point>,
+) -> Vec<bool> {
+    visible_attendees(
+        place,
+        &prob.attendees,
+        &blocking_circles(others, &prob.pillars),
+    )
 }
 
-fn play_together_scalar(prob: &Problem, sol: &Solution) -> Vec<f64> {
-    if !is_full_division_scoring(prob) {
-        return vec![1.0; sol.placements.len()];
-    }
-    sol.placements
+// Happiness of every attendee from musician `musician_idx` alone.
+pub fn happiness(prob: &Problem, sol: &Solution, musician_idx: usize, scale: f64) -> Vec<i64> {
+    let place = sol.placements[musician_idx];
+    let kind = prob.musicians[musician_idx];
+    let volume = sol.volumes[musician_idx];
+    let visible = visible_attendees_from(prob, place, others(&sol.placements, musician_idx));
+    prob.attendees
         .iter()
-        .zip(prob.musicians.iter())
-        .enumerate()
-        .map(|(i, (&pi, &ki))| {
-            let mut scalar = 1.0;
-            for (j, (&pj, &kj)) in sol.placements.iter().zip(prob.musicians.iter()).enumerate() {
-                if i == j || ki != kj {
-                    continue;
-                }
-                scalar += 1.0 / (pi - pj).length();
+        .zip(visible)
+        .map(|(attendee, vis)| {
+            if !vis {
+                return 0;
             }
-            scalar
+            (volume * impact_raw(attendee, kind, place) as f64 * scale).ceil() as i64
         })
         .collect()
 }
 
-pub fn score(prob: &Problem, sol: &Solution, quiet: bool) -> Result<i64> {
-    let n: usize = prob.attendees.len();
+pub fn is_full_division_scoring(prob: &Problem) -> bool {
+    !prob.pillars.is_empty()
+}
 
-    if !is_valid_answer(prob, sol) {
-        println!("invalid solution");
-        return Ok(0);
+pub fn play_together_scalar_of(prob: &Problem, placements: &[Point], i: usize) -> f64 {
+    if !is_full_division_scoring(prob) {
+        return 1.0;
+    }
+    let pi = placements[i];
+    let ki = prob.musicians[i];
+    let mut scalar = 1.0;
+    for (j, (&pj, &kj)) in placements.iter().zip(prob.musicians.iter()).enumerate() {
+        if i == j || ki != kj {
+            continue;
+        }
+        scalar += 1.0 / (pi - pj).length();
     }
+    scalar
+}
+
+fn play_together_scalar(prob: &Problem, sol: &Solution) -> Vec<f64> {
+    (0..sol.placements.len())
+        .into_par_iter()
+        .map(|i| play_together_scalar_of(prob, &sol.placements, i))
+        .collect()
+}
+
+pub fn score(prob: &Problem, sol: &Solution, quiet: bool) -> Result<i64> {
+    ensure_valid(prob, sol)?;
 
-    let mut score: i64 = 0;
     let scalar = play_together_scalar(prob, sol);
 
-    let pb = ProgressBar::new(n as u64);
-    for attendee in prob.attendees.iter() {
-        score += happiness(&attendee, &prob.musicians, &prob.pillars, sol, &scalar);
-        if !quiet {
+    let pb = if quiet {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(sol.placements.len() as u64)
+    };
+    // the sum of integers does not depend on how rayon splits the work
+    let score: i64 = scalar
+        .par_iter()
+        .enumerate()
+        .map(|(musician_idx, &scale)| {
+            let h: i64 = happiness(prob, sol, musician_idx, scale).iter().sum();
             pb.inc(1);
-        }
+            h
+        })
+        .sum();
+    pb.finish_with_message("finish calculation");
+    Ok(score)
+}
+
+#[derive(Error, Debug)]
+pub enum ScoreError {
+    #[error("Invalid solution:{}", format_violations(.0))]
+    InvalidSolution(Vec<ValidationError>),
+}
+
+#[derive(Serialize, Debug)]
+pub struct MusicianScore {
+    pub raw_impact: i64,
+    pub play_together: f64,
+    pub volume: f64,
+    pub contribution: i64,
+    pub visible_attendees: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ScoreBreakdown {
+    pub total: i64,
+    pub musicians: Vec<MusicianScore>,
+    pub attendee_happiness: Vec<i64>,
+}
+
+impl ScoreBreakdown {
+    pub fn save_to_file(&self, path: &PathBuf) -> Result<()> {
+        let f = File::create(path)?;
+        let writer = BufWriter::new(f);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
     }
-    if !quiet {
-        pb.finish_with_message("finish calculation");
+}
+
+pub fn score_breakdown(prob: &Problem, sol: &Solution) -> Result<ScoreBreakdown> {
+    ensure_valid(prob, sol)?;
+    let scalar = play_together_scalar(prob, sol);
+    let per_musician: Vec<_> = sol
+        .placements
+        .par_iter()
+        .enumerate()
+        .map(|(midx, &place)| {
+            let visible = visible_attendees_from(prob, place, others(&sol.placements, midx));
+            let kind = prob.musicians[midx];
+            let volume = sol.volumes[midx];
+            let mut raw_impact = 0;
+            let mut contribution = 0;
+            let mut happiness = vec![0; prob.attendees.len()];
+            for ((attendee, vis), h) in prob.attendees.iter().zip(&visible).zip(&mut happiness) {
+                if !vis {
+                    continue;
+                }
+                let imp = impact_raw(attendee, kind, place);
+                raw_impact += imp;
+                *h = (volume * imp as f64 * scalar[midx]).ceil() as i64;
+                contribution += *h;
+            }
+            let stat = MusicianScore {
+                raw_impact,
+                play_together: scalar[midx],
+                volume,
+                contribution,
+                visible_attendees: visible.iter().filter(|&&vis| vis).count(),
+            };
+            (stat, happiness)
+        })
+        .collect();
+    let mut attendee_happiness = vec![0; prob.attendees.len()];
+    let mut musicians = Vec::with_capacity(per_musician.len());
+    for (stat, happiness) in per_musician {
+        for (total, h) in attendee_happiness.iter_mut().zip(happiness) {
+            *total += h;
+        }
+        musicians.push(stat);
     }
-    Ok(score)
+    Ok(ScoreBreakdown {
+        total: musicians.iter().map(|m| m.contribution).sum(),
+        musicians,
+        attendee_happiness,
+    })
 }
 
-fn is_valid_answer(prob: &Problem, sol: &Solution) -> bool {
-    let mut is_valid = true;
+pub fn validate(prob: &Problem, sol: &Solution) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    if sol.placements.len() != prob.musicians.len() {
+        errors.push(ValidationError::PlacementCount {
+            expected: prob.musicians.len(),
+            actual: sol.placements.len(),
+        });
+    }
+    errors.extend(validate_volumes(sol));
     let stage_left = prob.stage_from().x;
     let stage_bottom = prob.stage_from().y;
     let stage_right = prob.stage_to().x;
     let stage_top = prob.stage_to().y;
     for (musician_idx, musician_point) in sol.placements.iter().enumerate() {
+        if musician_point.x.is_nan() || musician_point.y.is_nan() {
+            errors.push(ValidationError::NanCoordinate {
+                musician: musician_idx,
+            });
+            continue;
+        }
         if musician_point.x < stage_left + 10.
             || musician_point.x > stage_right - 10.
             || musician_point.y < stage_bottom + 10.
             || musician_point.y > stage_top - 10.
         {
-            is_valid = false;
-            println!(
-                "{musician_idx} is not inside the stage or too close to the edge of the stage"
-            );
+            errors.push(ValidationError::OutsideStage {
+                musician: musician_idx,
+            });
         }
-        for (check_musician_idx, check_musician_point) in sol.placements.iter().enumerate() {
-            if musician_idx == check_musician_idx {
-                continue;
-            }
-            if (*musician_point - *check_musician_point).norm() < 100.0 {
-                is_valid = false;
-                println!("{musician_idx} is not far enough from {check_musician_idx}");
+        for (check_musician_idx, check_musician_point) in
+            sol.placements.iter().enumerate().skip(musician_idx + 1)
+        {
+            let norm = (*musician_point - *check_musician_point).norm();
+            if norm < 100.0 {
+                errors.push(ValidationError::TooClose {
+                    musician: musician_idx,
+                    another: check_musician_idx,
+                    distance: norm.sqrt(),
+                });
             }
         }
     }
-    is_valid
+    errors
+}
+
+// Same rules as validate, for one musician moving to `place`.
+pub fn is_feasible_place(prob: &Problem, placements: &[Point], midx: usize, place: Point) -> bool {
+    let from = prob.stage_from();
+    let to = prob.stage_to();
+    let inside = place.x >= from.x + 10.
+        && place.x <= to.x - 10.
+        && place.y >= from.y + 10.
+        && place.y <= to.y - 10.;
+    inside
+        && placements
+            .iter()
+            .enumerate()
+            .all(|(idx, &p)| idx == midx || (p - place).norm() >= 100.0)
+}
+
+// Same checks as validate, with the violations reported as an error.
+pub fn ensure_valid(prob: &Problem, sol: &Solution) -> Result<()> {
+    let errors = validate(prob, sol);
+    if !errors.is_empty() {
+        return Err(ScoreError::InvalidSolution(errors).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::incremental::test::{random_placements, random_problem};
+    use rand::prelude::*;
+    use rand::rngs::SmallRng;
+
+    fn problem(pillars: Vec<Pillar>) -> Problem {
+        Problem {
+            room_width: 300.0,
+            room_height: 300.0,
+            stage_width: 100.0,
+            stage_height: 100.0,
+            stage_bottom_left: vec![100.0, 100.0],
+            musicians: vec![0, 1],
+            attendees: vec![],
+            pillars,
+        }
+    }
+
+    fn solution(placements: &[(f64, f64)]) -> Solution {
+        Solution {
+            placements: placements.iter().map(|&(x, y)| Point { x, y }).collect(),
+            volumes: vec![1.0; placements.len()],
+        }
+    }
+
+    #[test]
+    fn test_validate_overlap() {
+        let prob = problem(vec![]);
+        assert!(validate(&prob, &solution(&[(120.0, 120.0), (130.0, 120.0)])).is_empty());
+        let errors = validate(&prob, &solution(&[(120.0, 120.0), (129.0, 120.0)]));
+        assert!(matches!(
+            errors[..],
+            [ValidationError::TooClose {
+                musician: 0,
+                another: 1,
+                ..
+            }]
+        ));
+        assert!(ensure_valid(&prob, &solution(&[(120.0, 120.0), (129.0, 120.0)])).is_err());
+    }
+
+    #[test]
+    fn test_validate_stage_bounds() {
+        let prob = problem(vec![]);
+        assert!(validate(&prob, &solution(&[(110.0, 110.0), (190.0, 190.0)])).is_empty());
+        for outside in [(109.0, 150.0), (150.0, 191.0), (50.0, 50.0)] {
+            let errors = validate(&prob, &solution(&[(150.0, 150.0), outside]));
+            assert_eq!(errors, vec![ValidationError::OutsideStage { musician: 1 }]);
+        }
+    }
+
+    #[test]
+    fn test_validate_pillars() {
+        // pillars only block sound, musicians may stand next to or over them
+        let prob = problem(vec![Pillar {
+            center: (150.0, 150.0),
+            radius: 10.0,
+        }]);
+        assert!(validate(&prob, &solution(&[(150.0, 150.0), (165.0, 150.0)])).is_empty());
+        assert!(ensure_valid(&prob, &solution(&[(150.0, 150.0), (165.0, 150.0)])).is_ok());
+    }
+
+    #[test]
+    fn test_score_breakdown() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let prob = random_problem(&mut rng, true);
+        let sol = Solution {
+            placements: random_placements(&mut rng, &prob),
+            volumes: (0..prob.musicians.len())
+                .map(|_| rng.gen_range(0.0..10.0))
+                .collect(),
+        };
+        let breakdown = score_breakdown(&prob, &sol).unwrap();
+        assert_eq!(breakdown.total, score(&prob, &sol, true).unwrap());
+        let by_musician: i64 = breakdown.musicians.iter().map(|m| m.contribution).sum();
+        assert_eq!(by_musician, breakdown.total);
+        let by_attendee: i64 = breakdown.attendee_happiness.iter().sum();
+        assert_eq!(by_attendee, breakdown.total);
+    }
 }
@@ -0,0 +1,157 @@
+use crate::geometry::*;
+
+// Uniform grid over circles of the same radius.
+// Each circle is registered to every cell its (slightly enlarged) bounding box overlaps,
+// so walking the cells a segment passes through finds every circle it may cross.
+// score() no longer uses it since the visibility sweep replaced that path; ruin and hungarian
+// do, to find which circles each segment crosses rather than only whether one does.
+pub struct SpatialGrid {
+    origin: Point,
+    cell_size: f64,
+    cols: usize,
+    rows: usize,
+    cells: Vec<Vec<usize>>,
+}
+
+impl SpatialGrid {
+    pub fn new(centers: &[Point], radius: f64) -> SpatialGrid {
+        let margin = radius + 1.0;
+        let cell_size = 2.0 * radius.max(1.0);
+        let mut min = Point {
+            x: f64::INFINITY,
+            y: f64::INFINITY,
+        };
+        let mut max = Point {
+            x: f64::NEG_INFINITY,
+            y: f64::NEG_INFINITY,
+        };
+        for c in centers {
+            min.x = min.x.min(c.x);
+            min.y = min.y.min(c.y);
+            max.x = max.x.max(c.x);
+            max.y = max.y.max(c.y);
+        }
+        if centers.is_empty() {
+            min = Point { x: 0.0, y: 0.0 };
+            max = min;
+        }
+        let origin = min
+            - Point {
+                x: margin,
+                y: margin,
+            };
+        let cols = ((max.x - min.x + 2.0 * margin) / cell_size).ceil() as usize + 1;
+        let rows = ((max.y - min.y + 2.0 * margin) / cell_size).ceil() as usize + 1;
+        let mut grid = SpatialGrid {
+            origin,
+            cell_size,
+            cols,
+            rows,
+            cells: vec![Vec::new(); cols * rows],
+        };
+        for (idx, &c) in centers.iter().enumerate() {
+            let (c0, r0) = grid.cell_of(c.x - margin, c.y - margin);
+            let (c1, r1) = grid.cell_of(c.x + margin, c.y + margin);
+            for row in r0..=r1 {
+                for col in c0..=c1 {
+                    grid.cells[row * cols + col].push(idx);
+                }
+            }
+        }
+        grid
+    }
+
+    fn cell_of(&self, x: f64, y: f64) -> (usize, usize) {
+        let col = ((x - self.origin.x) / self.cell_size).floor();
+        let row = ((y - self.origin.y) / self.cell_size).floor();
+        let col = col.clamp(0.0, (self.cols - 1) as f64) as usize;
+        let row = row.clamp(0.0, (self.rows - 1) as f64) as usize;
+        (col, row)
+    }
+
+    // Calls `pred` for every circle registered to a cell the segment passes through,
+    // and returns true as soon as `pred` does. A circle may be visited more than once.
+    pub fn any_near_segment(
+        &self,
+        from: Point,
+        to: Point,
+        mut pred: impl FnMut(usize) -> bool,
+    ) -> bool {
+        let d = to - from;
+        let x_min = self.origin.x;
+        let y_min = self.origin.y;
+        let x_max = x_min + self.cols as f64 * self.cell_size;
+        let y_max = y_min + self.rows as f64 * self.cell_size;
+
+        // clip the segment to the grid area
+        let mut t0: f64 = 0.0;
+        let mut t1: f64 = 1.0;
+        for (p, q) in [
+            (-d.x, from.x - x_min),
+            (d.x, x_max - from.x),
+            (-d.y, from.y - y_min),
+            (d.y, y_max - from.y),
+        ] {
+            if p == 0.0 {
+                if q < 0.0 {
+                    return false;
+                }
+            } else if p < 0.0 {
+                t0 = t0.max(q / p);
+            } else {
+                t1 = t1.min(q / p);
+            }
+        }
+        if t0 > t1 {
+            return false;
+        }
+        let start = from + t0 * d;
+        let end = from + t1 * d;
+        let (mut col, mut row) = self.cell_of(start.x, start.y);
+        let (end_col, end_row) = self.cell_of(end.x, end.y);
+
+        let step_col: isize = if d.x > 0.0 { 1 } else { -1 };
+        let step_row: isize = if d.y > 0.0 { 1 } else { -1 };
+        let next_boundary = |idx: usize, step: isize, origin: f64| -> f64 {
+            let idx = if step > 0 { idx + 1 } else { idx };
+            origin + idx as f64 * self.cell_size
+        };
+        let mut t_max_col = if d.x != 0.0 {
+            (next_boundary(col, step_col, x_min) - from.x) / d.x
+        } else {
+            f64::INFINITY
+        };
+        let mut t_max_row = if d.y != 0.0 {
+            (next_boundary(row, step_row, y_min) - from.y) / d.y
+        } else {
+            f64::INFINITY
+        };
+        let t_delta_col = self.cell_size / d.x.abs();
+        let t_delta_row = self.cell_size / d.y.abs();
+
+        loop {
+            for &idx in &self.cells[row * self.cols + col] {
+                if pred(idx) {
+                    return true;
+                }
+            }
+            if col == end_col && row == end_row {
+                return false;
+            }
+            let step_by_col = if col == end_col {
+                false
+            } else if row == end_row {
+                true
+            } else {
+                t_max_col < t_max_row
+            };
+            if step_by_col {
+                col = col.wrapping_add_signed(step_col);
+                t_max_col += t_delta_col;
+            } else {
+                row = row.wrapping_add_signed(step_row);
+                t_max_row += t_delta_row;
+            }
+        }
+    }
+}
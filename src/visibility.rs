@@ -0,0 +1,143 @@
+use crate::common::*;
+use crate::geometry::*;
+use ordered_float::OrderedFloat;
+use std::collections::BTreeSet;
+use std::f64::consts::PI;
+
+// Angular intervals are widened by this amount so that rounding in atan2/asin never hides
+// a blocker; the final decision is always made by is_cross_line_circle.
+const ANGLE_EPS: f64 = 1e-7;
+const DIST_EPS: f64 = 1e-6;
+
+const OPEN: u8 = 0;
+const QUERY: u8 = 1;
+const CLOSE: u8 = 2;
+
+// Decides which attendees can see `place` when the given circles stand in the way.
+// Sweeps once around `place`, keeping the circles covering the current angle ordered by
+// their nearest distance. A query walks that order until a circle crosses the segment or the
+// circles get farther than the attendee, so it costs O(log M + k), where k counts the nearer
+// covering circles the segment misses. In the worst case k is M and the sweep is O(A * M).
+// For musicians alone, which are equal and disjoint, the nearest covering circle is also the
+// first one the segment enters, so k only counts circles grazed within ANGLE_EPS or straddling
+// the attendee's distance; pillars of other sizes can add more.
+// The result matches testing every segment against every circle with is_cross_line_circle.
+pub fn visible_attendees(place: Point, attendees: &[Attendee], blockers: &[Circle]) -> Vec<bool> {
+    let mut events = Vec::with_capacity(attendees.len() + 4 * blockers.len());
+    let mut always_active = Vec::new();
+    for (i, circle) in blockers.iter().enumerate() {
+        let v = circle.c - place;
+        let dist = v.length();
+        if dist <= circle.r + DIST_EPS {
+            always_active.push(i);
+            continue;
+        }
+        let center = v.y.atan2(v.x);
+        let half = (circle.r / dist).asin() + ANGLE_EPS;
+        let (lo, hi) = (center - half, center + half);
+        let mut push_interval = |lo: f64, hi: f64| {
+            events.push((lo, OPEN, i));
+            events.push((hi, CLOSE, i));
+        };
+        if lo < -PI {
+            push_interval(lo + 2. * PI, PI);
+            push_interval(-PI, hi);
+        } else if hi > PI {
+            push_interval(lo, PI);
+            push_interval(-PI, hi - 2. * PI);
+        } else {
+            push_interval(lo, hi);
+        }
+    }
+    for (j, attendee) in attendees.iter().enumerate() {
+        let v = attendee.place() - place;
+        events.push((v.y.atan2(v.x), QUERY, j));
+    }
+    events.sort_unstable_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    let near = |i: usize| OrderedFloat((blockers[i].c - place).length() - blockers[i].r);
+    let mut active = BTreeSet::new();
+    let mut visible = vec![true; attendees.len()];
+    for (_, kind, idx) in events {
+        match kind {
+            OPEN => {
+                active.insert((near(idx), idx));
+            }
+            CLOSE => {
+                active.remove(&(near(idx), idx));
+            }
+            _ => {
+                let attendee = &attendees[idx];
+                let line = Line {
+                    p1: attendee.place(),
+                    p2: place,
+                };
+                let reach = (attendee.place() - place).length() + DIST_EPS;
+                let blocked = always_active
+                    .iter()
+                    .any(|&i| is_cross_line_circle(line, blockers[i]))
+                    || active
+                        .iter()
+                        .take_while(|(near, _)| near.0 < reach)
+                        .any(|&(_, i)| is_cross_line_circle(line, blockers[i]));
+                visible[idx] = !blocked;
+            }
+        }
+    }
+    visible
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::prelude::*;
+    use rand::rngs::SmallRng;
+
+    fn attendee_at(x: f64, y: f64) -> Attendee {
+        Attendee {
+            x,
+            y,
+            tastes: vec![],
+        }
+    }
+
+    #[test]
+    fn test_visible_attendees() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let place = Point { x: 200.0, y: 150.0 };
+        let mut blockers: Vec<_> = (0..100)
+            .map(|_| Circle {
+                c: Point {
+                    x: rng.gen_range(100.0..300.0),
+                    y: rng.gen_range(100.0..200.0),
+                },
+                r: 5.0,
+            })
+            .filter(|c| (c.c - place).length() >= 10.0)
+            .collect();
+        blockers.push(Circle {
+            c: Point { x: 250.0, y: 250.0 },
+            r: 20.0,
+        });
+        let mut attendees: Vec<_> = (0..2000)
+            .map(|_| attendee_at(rng.gen_range(0.0..400.0), rng.gen_range(0.0..300.0)))
+            .collect();
+        // tangent to the circle around (190, 155), just inside and just outside of it
+        blockers.push(Circle {
+            c: Point { x: 190.0, y: 155.0 },
+            r: 5.0,
+        });
+        attendees.push(attendee_at(0.0, 150.0));
+        attendees.push(attendee_at(0.0, 150.1));
+        attendees.push(attendee_at(0.0, 149.9));
+        let visible = visible_attendees(place, &attendees, &blockers);
+        for (attendee, vis) in attendees.iter().zip(visible) {
+            let line = Line {
+                p1: attendee.place(),
+                p2: place,
+            };
+            let expected = blockers.iter().all(|&c| !is_cross_line_circle(line, c));
+            assert_eq!(expected, vis);
+        }
+    }
+}
@@ -98,7 +98,7 @@ fn vis_pillar(pillar: &Pillar) -> Circle {
 fn vis_pillars(pilars: &[Pillar]) -> Group {
     let mut g = Group::new();
     for pillar in pilars {
-        g = g.add(vis_pillar(&pillar));
+        g = g.add(vis_pillar(pillar));
     }
     g
 }
@@ -126,7 +126,7 @@ pub fn visualize(prob: &Problem, sol: &Solution, output: &PathBuf) -> Result<()>
     let max_kind = prob.attendees.first().unwrap().tastes.len();
     doc = doc.add(vis_attendees(&prob.attendees));
     doc = doc.add(vis_musicians(&prob.musicians, max_kind, &sol.placements));
-    let html = format!("<html><body>{}</body></html>", doc.to_string());
+    let html = format!("<html><body>{}</body></html>", doc);
     std::fs::write(output, html)?;
     Ok(())
 }
@@ -0,0 +1,48 @@
+use crate::common::*;
+use crate::score::*;
+use anyhow::Result;
+
+// Volume scales every term of a musician's contribution and nothing else,
+// so each musician is independently best at 10 if it contributes positively at all, else at 0.
+pub fn optimize_volume(prob: &Problem, sol: &Solution) -> Result<Solution> {
+    let loud = Solution {
+        placements: sol.placements.clone(),
+        volumes: vec![10.0; sol.placements.len()],
+    };
+    let breakdown = score_breakdown(prob, &loud)?;
+    let volumes = breakdown
+        .musicians
+        .iter()
+        .map(|m| if m.contribution > 0 { 10.0 } else { 0.0 })
+        .collect();
+    Ok(Solution {
+        placements: sol.placements.clone(),
+        volumes,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::incremental::test::{random_placements, random_problem};
+    use rand::prelude::*;
+    use rand::rngs::SmallRng;
+
+    #[test]
+    fn test_optimize_volume() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        for with_pillars in [false, true] {
+            let prob = random_problem(&mut rng, with_pillars);
+            let sol = Solution {
+                placements: random_placements(&mut rng, &prob),
+                volumes: (0..prob.musicians.len())
+                    .map(|_| rng.gen_range(0.0..10.0))
+                    .collect(),
+            };
+            let result = optimize_volume(&prob, &sol).unwrap();
+            assert!(validate_volumes(&result).is_empty());
+            assert!(result.volumes.iter().all(|v| (0.0..=10.0).contains(v)));
+            assert!(score(&prob, &result, true).unwrap() >= score(&prob, &sol, true).unwrap());
+        }
+    }
+}